@@ -1,2 +1,4 @@
 DROP TABLE Announcement;
+-- Announcement_new was created by hand before it was partitioned, it is left flat by the later down migrations
+DROP TABLE IF EXISTS announcement_new;
 DROP TYPE as_path_segment;
@@ -0,0 +1 @@
+DROP TABLE ingested_file;
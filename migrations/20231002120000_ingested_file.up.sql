@@ -0,0 +1,8 @@
+CREATE TABLE ingested_file
+(
+    url text primary key,
+    status text not null,
+    row_count bigint not null,
+    checksum bigint not null,
+    ingested_at timestamptz not null default now()
+);
@@ -0,0 +1,3 @@
+ALTER TABLE ingested_file
+    DROP COLUMN corrupt_records,
+    DROP COLUMN error;
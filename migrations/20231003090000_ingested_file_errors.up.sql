@@ -0,0 +1,3 @@
+ALTER TABLE ingested_file
+    ADD COLUMN corrupt_records bigint not null default 0,
+    ADD COLUMN error text;
@@ -0,0 +1,17 @@
+CREATE TABLE announcement_flat (LIKE announcement);
+INSERT INTO announcement_flat SELECT * FROM announcement;
+DROP TABLE announcement;
+ALTER TABLE announcement_flat RENAME TO announcement;
+ALTER TABLE announcement ADD PRIMARY KEY (id);
+CREATE INDEX ASN on Announcement (asn);
+CREATE INDEX WD on Announcement (withdrawal);
+CREATE INDEX TS on Announcement (timestamp);
+
+CREATE TABLE announcement_new_flat (LIKE announcement_new);
+INSERT INTO announcement_new_flat SELECT * FROM announcement_new;
+DROP TABLE announcement_new;
+ALTER TABLE announcement_new_flat RENAME TO announcement_new;
+
+DROP FUNCTION partition_announcement_rows(text, text);
+DROP FUNCTION index_announcement_partition(text);
+DROP FUNCTION announcement_partition(text, double precision);
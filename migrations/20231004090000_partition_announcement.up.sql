@@ -0,0 +1,67 @@
+-- One partition per UTC day, named <parent>_pYYYYMMDD, created on demand at ingest
+CREATE FUNCTION announcement_partition(parent text, ts double precision) RETURNS text
+    LANGUAGE plpgsql AS
+$$
+DECLARE
+    day  double precision := floor(ts / 86400) * 86400;
+    part text             := parent || '_p' || to_char(to_timestamp(day) AT TIME ZONE 'UTC', 'YYYYMMDD');
+BEGIN
+    IF to_regclass(part) IS NULL THEN
+        PERFORM pg_advisory_xact_lock(hashtext(part));
+        EXECUTE format('CREATE TABLE IF NOT EXISTS %I PARTITION OF %I FOR VALUES FROM (%s) TO (%s)',
+                       part, parent, day, day + 86400);
+    END IF;
+    RETURN part;
+END
+$$;
+
+-- Primary key and indexes of a single partition, only what is missing is added
+CREATE FUNCTION index_announcement_partition(part text) RETURNS void
+    LANGUAGE plpgsql AS
+$$
+BEGIN
+    IF NOT EXISTS (SELECT FROM pg_constraint WHERE conname = part || '_pkey') THEN
+        EXECUTE format('ALTER TABLE %I ADD PRIMARY KEY (id)', part);
+    END IF;
+    EXECUTE format('CREATE INDEX IF NOT EXISTS %I ON %I (asn)', part || '_asn', part);
+    EXECUTE format('CREATE INDEX IF NOT EXISTS %I ON %I (withdrawal)', part || '_wd', part);
+    EXECUTE format('CREATE INDEX IF NOT EXISTS %I ON %I (timestamp)', part || '_ts', part);
+END
+$$;
+
+-- Moves the rows of a flat table into day partitions of `parent` and drops it
+CREATE FUNCTION partition_announcement_rows(flat text, parent text) RETURNS void
+    LANGUAGE plpgsql AS
+$$
+DECLARE
+    day double precision;
+BEGIN
+    FOR day IN EXECUTE format('SELECT DISTINCT floor(timestamp / 86400) * 86400 FROM %I', flat)
+        LOOP
+            PERFORM announcement_partition(parent, day);
+        END LOOP;
+    EXECUTE format('INSERT INTO %I SELECT * FROM %I', parent, flat);
+    EXECUTE format('DROP TABLE %I', flat);
+END
+$$;
+
+ALTER TABLE Announcement RENAME TO announcement_flat;
+CREATE TABLE Announcement (LIKE announcement_flat) PARTITION BY RANGE (timestamp);
+SELECT partition_announcement_rows('announcement_flat', 'announcement');
+SELECT index_announcement_partition(inhrelid::regclass::text)
+FROM pg_inherits
+WHERE inhparent = 'announcement'::regclass;
+
+-- Announcement_new was created by hand until now
+DO
+$$
+BEGIN
+    IF to_regclass('announcement_new') IS NOT NULL THEN
+        ALTER TABLE announcement_new RENAME TO announcement_new_flat;
+        CREATE TABLE announcement_new (LIKE announcement) PARTITION BY RANGE (timestamp);
+        PERFORM partition_announcement_rows('announcement_new_flat', 'announcement_new');
+    ELSE
+        CREATE TABLE announcement_new (LIKE announcement) PARTITION BY RANGE (timestamp);
+    END IF;
+END
+$$;
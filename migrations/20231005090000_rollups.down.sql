@@ -0,0 +1,4 @@
+DROP VIEW origin_prefixes_daily;
+DROP TABLE peer_hourly;
+DROP TABLE prefix_daily;
+DROP TABLE prefix_hourly;
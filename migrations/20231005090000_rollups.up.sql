@@ -0,0 +1,34 @@
+-- Summaries updated by every ingest, kept when the raw announcements are pruned
+CREATE TABLE prefix_hourly
+(
+    hour          timestamptz not null,
+    prefix        inet        not null,
+    origin        bigint      not null, -- 0 for withdrawals, they carry no path
+    announcements bigint      not null,
+    withdrawals   bigint      not null,
+    PRIMARY KEY (hour, prefix, origin)
+);
+CREATE TABLE prefix_daily
+(
+    day           timestamptz not null,
+    prefix        inet        not null,
+    origin        bigint      not null,
+    announcements bigint      not null,
+    withdrawals   bigint      not null,
+    PRIMARY KEY (day, prefix, origin)
+);
+CREATE INDEX prefix_daily_origin on prefix_daily (origin, day);
+CREATE TABLE peer_hourly
+(
+    hour          timestamptz not null,
+    collector     text        not null,
+    peer_asn      bigint      not null,
+    announcements bigint      not null,
+    withdrawals   bigint      not null,
+    PRIMARY KEY (hour, collector, peer_asn)
+);
+CREATE VIEW origin_prefixes_daily AS
+SELECT day, origin, count(*) AS prefixes
+FROM prefix_daily
+WHERE announcements > 0
+GROUP BY day, origin;
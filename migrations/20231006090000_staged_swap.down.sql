@@ -0,0 +1,20 @@
+DROP FUNCTION rename_announcement_partition(text, text);
+
+CREATE OR REPLACE FUNCTION index_announcement_partition(part text) RETURNS void
+    LANGUAGE plpgsql AS
+$$
+BEGIN
+    IF NOT EXISTS (SELECT FROM pg_constraint WHERE conname = part || '_pkey') THEN
+        EXECUTE format('ALTER TABLE %I ADD PRIMARY KEY (id)', part);
+    END IF;
+    EXECUTE format('CREATE INDEX IF NOT EXISTS %I ON %I (asn)', part || '_asn', part);
+    EXECUTE format('CREATE INDEX IF NOT EXISTS %I ON %I (withdrawal)', part || '_wd', part);
+    EXECUTE format('CREATE INDEX IF NOT EXISTS %I ON %I (timestamp)', part || '_ts', part);
+END
+$$;
+
+ALTER TABLE ingested_file
+    DROP COLUMN promotion;
+DROP TABLE promoted_partition;
+DROP TABLE promotion;
+DROP TABLE staged_partition;
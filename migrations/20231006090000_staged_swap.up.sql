@@ -0,0 +1,73 @@
+-- Partitions of Announcement_new waiting to be promoted into Announcement, with the rows written into them
+CREATE TABLE staged_partition
+(
+    partition text primary key,
+    day       double precision not null,
+    row_count bigint           not null default 0
+);
+
+CREATE TABLE promotion
+(
+    id             bigserial primary key,
+    promoted_at    timestamptz not null default now(),
+    rolled_back_at timestamptz
+);
+
+-- The partitions a promotion attached to Announcement, and the ones they replaced, kept for a rollback
+CREATE TABLE promoted_partition
+(
+    promotion bigint           not null references promotion,
+    partition text             not null,
+    day       double precision not null,
+    previous  text,
+    PRIMARY KEY (promotion, partition)
+);
+
+-- Files loaded but not yet promoted have no promotion
+ALTER TABLE ingested_file
+    ADD COLUMN promotion bigint references promotion;
+
+-- Partitions keep their primary key when renamed, look it up by table rather than name
+CREATE OR REPLACE FUNCTION index_announcement_partition(part text) RETURNS void
+    LANGUAGE plpgsql AS
+$$
+BEGIN
+    IF NOT EXISTS (SELECT FROM pg_constraint WHERE conrelid = part::regclass AND contype = 'p') THEN
+        EXECUTE format('ALTER TABLE %I ADD PRIMARY KEY (id)', part);
+    END IF;
+    EXECUTE format('CREATE INDEX IF NOT EXISTS %I ON %I (asn)', part || '_asn', part);
+    EXECUTE format('CREATE INDEX IF NOT EXISTS %I ON %I (withdrawal)', part || '_wd', part);
+    EXECUTE format('CREATE INDEX IF NOT EXISTS %I ON %I (timestamp)', part || '_ts', part);
+END
+$$;
+
+-- Renames a partition together with the indexes added by index_announcement_partition
+CREATE FUNCTION rename_announcement_partition(part text, new_name text) RETURNS void
+    LANGUAGE plpgsql AS
+$$
+DECLARE
+    suffix text;
+BEGIN
+    EXECUTE format('ALTER TABLE %I RENAME TO %I', part, new_name);
+    FOREACH suffix IN ARRAY ARRAY ['_pkey', '_asn', '_wd', '_ts']
+        LOOP
+            EXECUTE format('ALTER INDEX IF EXISTS %I RENAME TO %I', part || suffix, new_name || suffix);
+        END LOOP;
+END
+$$;
+
+-- Whatever was loaded before is staged
+DO
+$$
+DECLARE
+    part  text;
+    total bigint;
+BEGIN
+    FOR part IN SELECT inhrelid::regclass::text FROM pg_inherits WHERE inhparent = 'announcement_new'::regclass
+        LOOP
+            EXECUTE format('SELECT count(*) FROM %I', part) INTO total;
+            INSERT INTO staged_partition (partition, day, row_count)
+            VALUES (part, extract(EPOCH FROM to_date(right(part, 8), 'YYYYMMDD')), total);
+        END LOOP;
+END
+$$;
@@ -0,0 +1,69 @@
+-- The random ids are gone, rows get ones derived from their hash
+CREATE OR REPLACE FUNCTION index_announcement_partition(part text) RETURNS void
+    LANGUAGE plpgsql AS
+$$
+BEGIN
+    IF NOT EXISTS (SELECT FROM pg_constraint WHERE conrelid = part::regclass AND contype = 'p') THEN
+        EXECUTE format('ALTER TABLE %I ADD PRIMARY KEY (id)', part);
+    END IF;
+    EXECUTE format('CREATE INDEX IF NOT EXISTS %I ON %I (asn)', part || '_asn', part);
+    EXECUTE format('CREATE INDEX IF NOT EXISTS %I ON %I (withdrawal)', part || '_wd', part);
+    EXECUTE format('CREATE INDEX IF NOT EXISTS %I ON %I (timestamp)', part || '_ts', part);
+END
+$$;
+
+CREATE OR REPLACE FUNCTION announcement_partition(parent text, ts double precision) RETURNS text
+    LANGUAGE plpgsql AS
+$$
+DECLARE
+    day  double precision := floor(ts / 86400) * 86400;
+    part text             := parent || '_p' || to_char(to_timestamp(day) AT TIME ZONE 'UTC', 'YYYYMMDD');
+BEGIN
+    IF to_regclass(part) IS NULL THEN
+        PERFORM pg_advisory_xact_lock(hashtext(part));
+        EXECUTE format('CREATE TABLE IF NOT EXISTS %I PARTITION OF %I FOR VALUES FROM (%s) TO (%s)',
+                       part, parent, day, day + 86400);
+    END IF;
+    RETURN part;
+END
+$$;
+
+DO
+$$
+DECLARE
+    part text;
+    pkey text;
+BEGIN
+    FOR part IN SELECT c.relname
+                FROM pg_class AS c
+                WHERE c.relkind = 'r'
+                  AND c.relname ~ '^announcement(_new)?_p\d{8}(_prev\d+)?$'
+        LOOP
+            FOR pkey IN SELECT conname FROM pg_constraint WHERE conrelid = part::regclass AND contype = 'p'
+                LOOP
+                    EXECUTE format('ALTER TABLE %I DROP CONSTRAINT %I', part, pkey);
+                END LOOP;
+        END LOOP;
+
+    ALTER TABLE announcement
+        ALTER COLUMN id TYPE uuid USING md5(id::text)::uuid;
+    ALTER TABLE announcement_new
+        ALTER COLUMN id TYPE uuid USING md5(id::text)::uuid;
+    FOR part IN SELECT c.relname
+                FROM pg_class AS c
+                WHERE c.relkind = 'r'
+                  AND c.relname ~ '^announcement_p\d{8}_prev\d+$'
+        LOOP
+            EXECUTE format('ALTER TABLE %I ALTER COLUMN id TYPE uuid USING md5(id::text)::uuid', part);
+        END LOOP;
+
+    -- staged partitions are indexed when they are promoted
+    FOR part IN SELECT c.relname
+                FROM pg_class AS c
+                WHERE c.relkind = 'r'
+                  AND c.relname ~ '^announcement_p\d{8}(_prev\d+)?$'
+        LOOP
+            PERFORM index_announcement_partition(part);
+        END LOOP;
+END
+$$;
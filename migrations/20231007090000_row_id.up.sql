@@ -0,0 +1,73 @@
+-- Rows are identified by a hash of their content, only unique together with the timestamp (see `row_id`).
+-- The key leads with the timestamp so loads insert in roughly key order, and it is added when a partition is
+-- created so loading rows again is deduplicated on insert.
+DO
+$$
+DECLARE
+    part text;
+    pkey text;
+BEGIN
+    -- partitions of both tables and the ones kept for a rollback
+    FOR part IN SELECT c.relname
+                FROM pg_class AS c
+                WHERE c.relkind = 'r'
+                  AND c.relname ~ '^announcement(_new)?_p\d{8}(_prev\d+)?$'
+        LOOP
+            FOR pkey IN SELECT conname FROM pg_constraint WHERE conrelid = part::regclass AND contype = 'p'
+                LOOP
+                    EXECUTE format('ALTER TABLE %I DROP CONSTRAINT %I', part, pkey);
+                END LOOP;
+            EXECUTE format('DROP INDEX IF EXISTS %I', part || '_ts');
+        END LOOP;
+
+    ALTER TABLE announcement
+        ALTER COLUMN id TYPE bigint USING ('x' || left(md5(id::text), 16))::bit(64)::bigint;
+    ALTER TABLE announcement_new
+        ALTER COLUMN id TYPE bigint USING ('x' || left(md5(id::text), 16))::bit(64)::bigint;
+    FOR part IN SELECT c.relname
+                FROM pg_class AS c
+                WHERE c.relkind = 'r'
+                  AND c.relname ~ '^announcement_p\d{8}_prev\d+$'
+        LOOP
+            EXECUTE format('ALTER TABLE %I ALTER COLUMN id TYPE bigint USING (''x'' || left(md5(id::text), 16))::bit(64)::bigint',
+                           part);
+        END LOOP;
+
+    FOR part IN SELECT c.relname
+                FROM pg_class AS c
+                WHERE c.relkind = 'r'
+                  AND c.relname ~ '^announcement(_new)?_p\d{8}(_prev\d+)?$'
+        LOOP
+            EXECUTE format('ALTER TABLE %I ADD PRIMARY KEY (timestamp, id)', part);
+        END LOOP;
+END
+$$;
+
+CREATE OR REPLACE FUNCTION announcement_partition(parent text, ts double precision) RETURNS text
+    LANGUAGE plpgsql AS
+$$
+DECLARE
+    day  double precision := floor(ts / 86400) * 86400;
+    part text             := parent || '_p' || to_char(to_timestamp(day) AT TIME ZONE 'UTC', 'YYYYMMDD');
+BEGIN
+    IF to_regclass(part) IS NULL THEN
+        PERFORM pg_advisory_xact_lock(hashtext(part));
+        EXECUTE format('CREATE TABLE IF NOT EXISTS %I PARTITION OF %I (PRIMARY KEY (timestamp, id)) FOR VALUES FROM (%s) TO (%s)',
+                       part, parent, day, day + 86400);
+    END IF;
+    RETURN part;
+END
+$$;
+
+-- The primary key leads with the timestamp, it serves timestamp lookups
+CREATE OR REPLACE FUNCTION index_announcement_partition(part text) RETURNS void
+    LANGUAGE plpgsql AS
+$$
+BEGIN
+    IF NOT EXISTS (SELECT FROM pg_constraint WHERE conrelid = part::regclass AND contype = 'p') THEN
+        EXECUTE format('ALTER TABLE %I ADD PRIMARY KEY (timestamp, id)', part);
+    END IF;
+    EXECUTE format('CREATE INDEX IF NOT EXISTS %I ON %I (asn)', part || '_asn', part);
+    EXECUTE format('CREATE INDEX IF NOT EXISTS %I ON %I (withdrawal)', part || '_wd', part);
+END
+$$;
@@ -0,0 +1,40 @@
+DROP FUNCTION announcement_partition(text, timestamptz);
+CREATE FUNCTION announcement_partition(parent text, ts double precision) RETURNS text
+    LANGUAGE plpgsql AS
+$$
+DECLARE
+    day  double precision := floor(ts / 86400) * 86400;
+    part text             := parent || '_p' || to_char(to_timestamp(day) AT TIME ZONE 'UTC', 'YYYYMMDD');
+BEGIN
+    IF to_regclass(part) IS NULL THEN
+        PERFORM pg_advisory_xact_lock(hashtext(part));
+        EXECUTE format('CREATE TABLE IF NOT EXISTS %I PARTITION OF %I (PRIMARY KEY (timestamp, id)) FOR VALUES FROM (%s) TO (%s)',
+                       part, parent, day, day + 86400);
+    END IF;
+    RETURN part;
+END
+$$;
+
+CREATE FUNCTION partition_announcement_rows(flat text, parent text) RETURNS void
+    LANGUAGE plpgsql AS
+$$
+DECLARE
+    day double precision;
+BEGIN
+    FOR day IN EXECUTE format('SELECT DISTINCT floor(timestamp / 86400) * 86400 FROM %I', flat)
+        LOOP
+            PERFORM announcement_partition(parent, day);
+        END LOOP;
+    EXECUTE format('INSERT INTO %I SELECT * FROM %I', parent, flat);
+    EXECUTE format('DROP TABLE %I', flat);
+END
+$$;
+
+ALTER TABLE promoted_partition
+    ALTER COLUMN day TYPE double precision USING extract(EPOCH FROM day);
+ALTER TABLE staged_partition
+    ALTER COLUMN day TYPE double precision USING extract(EPOCH FROM day);
+
+SELECT retype_announcement('announcement_new', 'double precision', 'extract(EPOCH FROM timestamp)');
+SELECT retype_announcement('announcement', 'double precision', 'extract(EPOCH FROM timestamp)');
+DROP FUNCTION retype_announcement(text, text, text);
@@ -0,0 +1,68 @@
+-- Timestamps become timestamptz, exact to the microsecond where double precision seconds were not.
+-- A partition key can not change type in place, so every partition is detached, converted and attached
+-- to a new parent.
+CREATE FUNCTION retype_announcement(parent text, to_type text, convert text) RETURNS void
+    LANGUAGE plpgsql AS
+$$
+DECLARE
+    old  text := parent || '_old';
+    part text;
+    day  timestamptz;
+BEGIN
+    EXECUTE format('ALTER TABLE %I RENAME TO %I', parent, old);
+    EXECUTE format('CREATE TABLE %I (id bigint not null, asn bigint not null, withdrawal boolean not null, '
+                       || 'timestamp %s not null, prefix inet not null, as_path_segments as_path_segment[] not null) '
+                       || 'PARTITION BY RANGE (timestamp)', parent, to_type);
+    FOR part IN SELECT inhrelid::regclass::text FROM pg_inherits WHERE inhparent = old::regclass
+        LOOP
+            EXECUTE format('ALTER TABLE %I DETACH PARTITION %I', old, part);
+            EXECUTE format('ALTER TABLE %I ALTER COLUMN timestamp TYPE %s USING %s', part, to_type, convert);
+            day := to_timestamp(extract(EPOCH FROM to_date(right(part, 8), 'YYYYMMDD')));
+            IF to_type = 'timestamptz' THEN
+                EXECUTE format('ALTER TABLE %I ATTACH PARTITION %I FOR VALUES FROM (%L) TO (%L)',
+                               parent, part, day, day + interval '24 hours');
+            ELSE
+                EXECUTE format('ALTER TABLE %I ATTACH PARTITION %I FOR VALUES FROM (%s) TO (%s)',
+                               parent, part, extract(EPOCH FROM day), extract(EPOCH FROM day) + 86400);
+            END IF;
+        END LOOP;
+    EXECUTE format('DROP TABLE %I', old);
+
+    -- partitions kept for a rollback are attached again later
+    FOR part IN SELECT c.relname
+                FROM pg_class AS c
+                WHERE c.relkind = 'r'
+                  AND c.relname ~ ('^' || parent || '_p\d{8}_prev\d+$')
+        LOOP
+            EXECUTE format('ALTER TABLE %I ALTER COLUMN timestamp TYPE %s USING %s', part, to_type, convert);
+        END LOOP;
+END
+$$;
+
+SELECT retype_announcement('announcement', 'timestamptz', 'to_timestamp(timestamp)');
+SELECT retype_announcement('announcement_new', 'timestamptz', 'to_timestamp(timestamp)');
+
+ALTER TABLE staged_partition
+    ALTER COLUMN day TYPE timestamptz USING to_timestamp(day);
+ALTER TABLE promoted_partition
+    ALTER COLUMN day TYPE timestamptz USING to_timestamp(day);
+
+-- Only used to partition the tables in the first place
+DROP FUNCTION partition_announcement_rows(text, text);
+
+DROP FUNCTION announcement_partition(text, double precision);
+CREATE FUNCTION announcement_partition(parent text, ts timestamptz) RETURNS text
+    LANGUAGE plpgsql AS
+$$
+DECLARE
+    day  timestamptz := to_timestamp(floor(extract(EPOCH FROM ts) / 86400) * 86400);
+    part text        := parent || '_p' || to_char(day AT TIME ZONE 'UTC', 'YYYYMMDD');
+BEGIN
+    IF to_regclass(part) IS NULL THEN
+        PERFORM pg_advisory_xact_lock(hashtext(part));
+        EXECUTE format('CREATE TABLE IF NOT EXISTS %I PARTITION OF %I (PRIMARY KEY (timestamp, id)) FOR VALUES FROM (%L) TO (%L)',
+                       part, parent, day, day + interval '24 hours');
+    END IF;
+    RETURN part;
+END
+$$;
@@ -0,0 +1,30 @@
+DROP VIEW origin_prefixes_daily;
+
+DO
+$$
+DECLARE
+    rollup text;
+    key    text;
+BEGIN
+    FOR rollup, key IN VALUES ('prefix_hourly', 'hour, prefix, origin'),
+                              ('prefix_daily', 'day, prefix, origin'),
+                              ('peer_hourly', 'hour, collector, peer_asn')
+        LOOP
+            EXECUTE format('CREATE TEMP TABLE folded ON COMMIT DROP AS '
+                               || 'SELECT %s, sum(announcements)::bigint AS announcements, '
+                               || 'sum(withdrawals)::bigint AS withdrawals FROM %I GROUP BY %s',
+                           key, rollup, key);
+            EXECUTE format('TRUNCATE %I', rollup);
+            EXECUTE format('ALTER TABLE %I DROP COLUMN promotion', rollup);
+            EXECUTE format('INSERT INTO %I (%s, announcements, withdrawals) SELECT * FROM folded', rollup, key);
+            EXECUTE format('ALTER TABLE %I ADD PRIMARY KEY (%s)', rollup, key);
+            DROP TABLE folded;
+        END LOOP;
+END
+$$;
+
+CREATE VIEW origin_prefixes_daily AS
+SELECT day, origin, count(*) AS prefixes
+FROM prefix_daily
+WHERE announcements > 0
+GROUP BY day, origin;
@@ -0,0 +1,32 @@
+-- Rollups keep the counts of every promotion apart, so a discard or a rollback takes its counts out again and
+-- the files it forgets are not counted twice when loaded again. 0 holds what is staged, -1 what was counted
+-- before and can no longer be told apart. Readers sum over promotions.
+DROP VIEW origin_prefixes_daily;
+
+DO
+$$
+DECLARE
+    rollup text;
+    key    text;
+BEGIN
+    FOR rollup, key IN VALUES ('prefix_hourly', 'hour, prefix, origin'),
+                              ('prefix_daily', 'day, prefix, origin'),
+                              ('peer_hourly', 'hour, collector, peer_asn')
+        LOOP
+            EXECUTE format('ALTER TABLE %I ADD COLUMN promotion bigint not null default 0', rollup);
+            IF EXISTS (SELECT FROM promotion) THEN
+                EXECUTE format('UPDATE %I SET promotion = -1', rollup);
+            END IF;
+            EXECUTE format('ALTER TABLE %I DROP CONSTRAINT %I', rollup, rollup || '_pkey');
+            EXECUTE format('ALTER TABLE %I ADD PRIMARY KEY (%s, promotion)', rollup, key);
+        END LOOP;
+END
+$$;
+
+CREATE VIEW origin_prefixes_daily AS
+SELECT day, origin, count(*) AS prefixes
+FROM (SELECT day, origin, prefix
+      FROM prefix_daily
+      GROUP BY day, origin, prefix
+      HAVING sum(announcements) > 0) AS announced
+GROUP BY day, origin;
@@ -1,102 +1,323 @@
 // Logs and Errors
-use log::{error, info};
+use log::{debug, error, info, warn};
 
 // BGP data
-use uuid::Uuid;
-
-use crate::db_writer::types::{APSegments, ASPathSeg, DELIMITER};
+use crate::db_writer::types::{from_secs_f64, to_micros, ASPathSeg, Announcement};
+use ipnetwork::IpNetwork;
 use bgpkit_broker::BgpkitBroker;
 use bgpkit_parser::{
     models::{AsPathSegment, ElemType},
-    BgpkitParser,
+    BgpElem, BgpkitParser, Elementor, ParserError,
 };
 
 // Data Processing
-use crossbeam_channel::Sender;
+use crate::config::Collectors;
+use crate::pipeline::{rows_bytes, BatchSize, FileBatch, FileSender, FromWriter, WriterSender};
+use crossbeam_channel::Receiver;
 use rayon::prelude::*;
-const CHUNK_SIZE: usize = 12;
-pub const EOF: [u8; 5] = [0, 0, 0, 0, 0];
-pub const EOW: [u8; 5] = [0, 0, 0, 0, 1];
-pub fn collect_bgp(start: u64, end: u64) -> BgpkitBroker {
-    let broker = BgpkitBroker::new()
-        .project("riperis")
-        .collector_id("rrc25")
+use std::collections::HashSet;
+use std::io::Read;
+use std::net::IpAddr;
+use std::time::Duration;
+const RETRIES: u32 = 4;
+const RETRY_BACKOFF: Duration = Duration::from_secs(2);
+pub fn collect_bgp(collectors: &Collectors) -> BgpkitBroker {
+    BgpkitBroker::new()
+        .project(collectors.project.as_str())
+        .collector_id(collectors.collectors.join(",").as_str())
         //.data_type("update")
-        .ts_start(start.to_string().as_str())
-        .ts_end(end.to_string().as_str())
+        .ts_start(collectors.start.to_string().as_str())
+        .ts_end(collectors.end.to_string().as_str())
         .page(1)
-        .page_size(100);
-    return broker;
+        .page_size(100)
+}
+
+/// Outcome of parsing one MRT file, see [`FileReport::status`]
+#[derive(Debug, Clone)]
+pub struct FileReport {
+    pub url: String,
+    pub collector: String,
+    pub rows: usize,
+    pub corrupt_records: usize,
+    pub error: Option<String>,
+}
+
+impl FileReport {
+    /// `failed` when the file could not be opened or read to the end after [`RETRIES`] attempts,
+    /// `partial` when some records were corrupt and skipped, `loaded` otherwise.
+    pub fn status(&self) -> &'static str {
+        if self.error.is_some() {
+            "failed"
+        } else if self.corrupt_records > 0 {
+            "partial"
+        } else {
+            "loaded"
+        }
+    }
 }
 
-pub fn parse_bgp(broker: BgpkitBroker, sender: Sender<Vec<u8>>) -> Result<(), anyhow::Error> {
+/// Parses every file listed by `broker` into `sender`, `chunk_size` files at a time, streaming each file to a
+/// writer in batches of `size`, and reports how each file went, including whether the writers managed to copy it. Files in `ingested` were
+/// fully loaded by an earlier run and are skipped.
+pub fn parse_bgp(
+    broker: BgpkitBroker,
+    ingested: &HashSet<String>,
+    sender: WriterSender,
+    progress: Receiver<FromWriter>,
+    size: BatchSize,
+    chunk_size: usize,
+) -> Result<Vec<FileReport>, anyhow::Error> {
     // make copy of sender for each par iter?
     use std::time::Instant;
-    let urls = broker.into_iter().map(|x| x.url).collect::<Vec<String>>();
-    let chunk_count = urls.iter().count().div_ceil(CHUNK_SIZE);
+    let (urls, skipped): (Vec<_>, Vec<_>) = broker
+        .into_iter()
+        .map(|x| (x.url, x.collector_id))
+        .partition(|(url, _)| !ingested.contains(url));
+    if !skipped.is_empty() {
+        info!("Skipping {} files that were already ingested", skipped.len());
+    }
+    info!("Batching {} rows or {:.1} MB per file at a time", size.rows, size.bytes as f64 / 1e6);
+    let chunk_count = urls.len().div_ceil(chunk_size);
     let mut index = 0usize;
+    let mut reports = Vec::with_capacity(urls.len());
 
-    urls.chunks(CHUNK_SIZE).for_each(|chunketh| {
+    for chunketh in urls.chunks(chunk_size) {
         index += 1;
         info!("v-- {index}/{chunk_count} chunk processing --v");
         let now = Instant::now();
-        chunketh.par_iter()
-            .filter_map(|url| {info!("--- parsing {}", url.as_str()); BgpkitParser::new(url.as_str()).ok()})
-            .for_each_with(sender.clone(), |tx, parser| {
-               let data = parser.into_elem_iter()
-                   .flat_map(|elem| {
-                       format!("{ID}{DELIMITER}{ASN}{DELIMITER}{WITHDRAW}{DELIMITER}{TIMESTAMP:?}{DELIMITER}{PREFIX}{DELIMITER}{AS_PATH}\n",
-                               ID = Uuid::new_v4(),
-                               ASN = elem.peer_asn.asn,
-                               WITHDRAW = match elem.elem_type {
-                                   ElemType::ANNOUNCE => 0,
-                                   ElemType::WITHDRAW => 1,
-                               },
-                               TIMESTAMP = elem.timestamp,
-                               PREFIX = elem.prefix.prefix.addr().to_string() + "/" + &*elem.prefix.prefix.prefix_len().to_string(),
-                               AS_PATH = APSegments(
-                                   match elem.as_path {
-                                       None => vec![],
-                                       Some(as_p) => as_p
-                                           .segments
-                                           .iter()
-                                           .map(|as_p_seg| match as_p_seg {
-                                               AsPathSegment::AsSequence(x) => ASPathSeg {
-                                                   seq: true,
-                                                   confed: false,
-                                                   as_path: x.par_iter().map(|&y| i64::from(y.asn)).collect(),
-                                               },
-                                               AsPathSegment::AsSet(x) => ASPathSeg {
-                                                   seq: false,
-                                                   confed: false,
-                                                   as_path: x.par_iter().map(|&y| i64::from(y.asn)).collect(),
-                                               },
-                                               AsPathSegment::ConfedSequence(x) => ASPathSeg {
-                                                   seq: true,
-                                                   confed: true,
-                                                   as_path: x.par_iter().map(|&y| i64::from(y.asn)).collect(),
-                                               },
-                                               AsPathSegment::ConfedSet(x) => ASPathSeg {
-                                                   seq: false,
-                                                   confed: true,
-                                                   as_path: x.par_iter().map(|&y| i64::from(y.asn)).collect(),
-                                               },
-                                           })
-                                           .collect::<Vec<ASPathSeg>>(),
-                                   }
-                               )
-                       ).into_bytes()
-                   })
-                   .collect::<Vec<u8>>();
-               match tx.send(data) {
-                   Ok(_) => {}
-                   Err(e) => { error!("Channel Disconnected, data:\n\t{e}"); }
+        let (mut chunk_reports, opened): (Vec<FileReport>, Vec<bool>) = chunketh.par_iter()
+            .map_with(sender.clone(), |tx, (url, collector)| {
+               info!("--- parsing {}", url.as_str());
+               match tx.open_file(url, collector) {
+                   Ok(file) => (parse_file(url, collector, &file, size), true),
+                   Err(e) => {
+                       error!("{e}");
+                       let report = FileReport {
+                           url: url.to_string(),
+                           collector: collector.to_string(),
+                           rows: 0,
+                           corrupt_records: 0,
+                           error: Some(e.to_string()),
+                       };
+                       (report, false)
+                   }
                }
-        });
-        sender.send(Vec::from(EOW)).expect("Could not send EOW");
+        }).unzip();
+
+        // wait for the writers to catch up, a file only counts once it is in the db
+        let expected = opened.iter().filter(|&&o| o).count();
+        let (mut copied, mut failed) = (0usize, 0usize);
+        while copied + failed < expected {
+            match progress.recv()? {
+                FromWriter::Copied { url, rows } => {
+                    copied += 1;
+                    debug!("--- copied {rows} rows of {url}");
+                }
+                FromWriter::Failed { url, error } => {
+                    failed += 1;
+                    if let Some(report) = chunk_reports.iter_mut().find(|r| r.url == url) {
+                        report.error.get_or_insert(error);
+                    }
+                }
+            }
+        }
+        reports.append(&mut chunk_reports);
         let elapsed = now.elapsed();
-        info!("^-- {index}/{chunk_count} Done in: {:.2?} --^", elapsed);
-    });
-    sender.send(Vec::from(EOF)).expect("Could not send EOF");
-    Ok(())
+        info!("^-- {index}/{chunk_count} Done in: {:.2?}, {copied} files copied, {failed} failed --^", elapsed);
+    }
+    Ok(reports)
+}
+
+/// Why a file could not be read to the end
+enum ReadError {
+    Parser(ParserError),
+    /// The writer gave up on the file, reading it again would not help
+    Writer(anyhow::Error),
+}
+
+/// Downloads and parses one MRT file into `file`, retrying with exponential backoff when the file can not be
+/// opened or the download breaks off, the writer drops what it got of a failed attempt.
+/// Corrupt records are skipped and counted instead of ending the file.
+fn parse_file(url: &str, collector: &str, file: &FileSender, size: BatchSize) -> FileReport {
+    let mut report = FileReport {
+        url: url.to_string(),
+        collector: collector.to_string(),
+        rows: 0,
+        corrupt_records: 0,
+        error: None,
+    };
+    let mut backoff = RETRY_BACKOFF;
+    for attempt in 1..=RETRIES {
+        let mut writer_gone = false;
+        let result = BgpkitParser::new(url)
+            .map_err(|e| format!("could not open file, {e}"))
+            .and_then(|parser| {
+                parse_records(parser, collector, file, size).map_err(|e| match e {
+                    ReadError::Parser(e) => format!("could not read file to the end, {e}"),
+                    ReadError::Writer(e) => {
+                        writer_gone = true;
+                        e.to_string()
+                    }
+                })
+            });
+        match result {
+            Ok((rows, corrupt_records)) => {
+                report.rows = rows;
+                report.corrupt_records = corrupt_records;
+                if corrupt_records > 0 {
+                    warn!("--- skipped {corrupt_records} corrupt records in {url}");
+                }
+                break;
+            }
+            Err(e) if attempt < RETRIES && !writer_gone => {
+                warn!("--- attempt {attempt}/{RETRIES} of {url} failed, retrying in {backoff:?}: {e}");
+                if let Err(e) = file.send(FileBatch::Restart) {
+                    report.error = Some(e.to_string());
+                    break;
+                }
+                std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+            Err(e) => {
+                error!("--- giving up on {url} after {attempt} attempts: {e}");
+                report.error = Some(e);
+                break;
+            }
+        }
+    }
+    // a writer that gave up has already recorded the file as failed
+    let _ = file.send(FileBatch::Done(report.clone()));
+    report
+}
+
+/// Walks the records by hand rather than through [`BgpkitParser::into_elem_iter`], which silently drops corrupt
+/// records and stops quietly on a broken download. Rows go to `file` in batches of `size`.
+/// Returns the row and the corrupt record counts.
+fn parse_records<R: Read>(
+    mut parser: BgpkitParser<R>,
+    collector: &str,
+    file: &FileSender,
+    size: BatchSize,
+) -> Result<(usize, usize), ReadError> {
+    let mut elementor = Elementor::new();
+    let mut batch = Vec::with_capacity(size.rows);
+    let mut batch_bytes = 0usize;
+    let (mut rows, mut corrupt) = (0usize, 0usize);
+    loop {
+        match parser.next_record() {
+            Ok(record) => {
+                let start = batch.len();
+                batch.extend(
+                    elementor
+                        .record_to_elems(record)
+                        .into_iter()
+                        .map(|elem| elem_to_announcement(elem, collector)),
+                );
+                batch_bytes += rows_bytes(&batch[start..]);
+                if batch.len() >= size.rows || batch_bytes >= size.bytes {
+                    rows += batch.len();
+                    batch_bytes = 0;
+                    let full = std::mem::replace(&mut batch, Vec::with_capacity(size.rows));
+                    file.send(FileBatch::Rows(full)).map_err(ReadError::Writer)?;
+                }
+            }
+            Err(e) => match e.error {
+                ParserError::EofExpected => break,
+                ParserError::ParseError(_)
+                | ParserError::TruncatedMsg(_)
+                | ParserError::Unsupported(_) => corrupt += 1,
+                err => return Err(ReadError::Parser(err)),
+            },
+        }
+    }
+    if !batch.is_empty() {
+        rows += batch.len();
+        file.send(FileBatch::Rows(batch)).map_err(ReadError::Writer)?;
+    }
+    Ok((rows, corrupt))
+}
+
+/// Turns a single [`BgpElem`] seen by `collector` into a row of the `Announcement` table.
+/// Shared by the MRT file parser and the live feeds so both produce identical rows for the writer.
+pub(crate) fn elem_to_announcement(elem: BgpElem, collector: &str) -> Announcement {
+    let peer_ip = elem.peer_ip;
+    let mut row = Announcement {
+        id: 0,
+        asn: i64::from(elem.peer_asn.asn),
+        withdrawal: match elem.elem_type {
+            ElemType::ANNOUNCE => false,
+            ElemType::WITHDRAW => true,
+        },
+        timestamp: from_secs_f64(elem.timestamp),
+        prefix: IpNetwork::new(elem.prefix.prefix.addr(), elem.prefix.prefix.prefix_len())
+            .expect("ipnet only holds valid prefix lengths"),
+        as_path_segments: match elem.as_path {
+            None => vec![],
+            Some(as_p) => as_p
+                .segments
+                .iter()
+                .map(|as_p_seg| match as_p_seg {
+                    AsPathSegment::AsSequence(x) => ASPathSeg {
+                        seq: true,
+                        confed: false,
+                        as_path: x.par_iter().map(|&y| i64::from(y.asn)).collect(),
+                    },
+                    AsPathSegment::AsSet(x) => ASPathSeg {
+                        seq: false,
+                        confed: false,
+                        as_path: x.par_iter().map(|&y| i64::from(y.asn)).collect(),
+                    },
+                    AsPathSegment::ConfedSequence(x) => ASPathSeg {
+                        seq: true,
+                        confed: true,
+                        as_path: x.par_iter().map(|&y| i64::from(y.asn)).collect(),
+                    },
+                    AsPathSegment::ConfedSet(x) => ASPathSeg {
+                        seq: false,
+                        confed: true,
+                        as_path: x.par_iter().map(|&y| i64::from(y.asn)).collect(),
+                    },
+                })
+                .collect::<Vec<ASPathSeg>>(),
+        },
+    };
+    row.id = row_id(collector, peer_ip, &row);
+    row
+}
+
+/// Deterministic id of a row, the same update seen by the same collector and peer always gets the same id,
+/// so loading a file again can be deduplicated. It is only unique together with the timestamp.
+/// 64 bit FNV-1a with a final mix, unlike [`std::hash::Hash`] it is stable across builds and platforms.
+fn row_id(collector: &str, peer_ip: IpAddr, row: &Announcement) -> i64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    let mut write = |bytes: &[u8]| {
+        for &b in bytes {
+            hash = (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    };
+    write(collector.as_bytes());
+    write(&[0xff]); // no collector name holds it, ends the name
+    match peer_ip {
+        IpAddr::V4(ip) => write(&ip.octets()),
+        IpAddr::V6(ip) => write(&ip.octets()),
+    }
+    write(&row.asn.to_be_bytes());
+    write(&[u8::from(row.withdrawal)]);
+    write(&to_micros(row.timestamp).to_be_bytes());
+    match row.prefix.ip() {
+        IpAddr::V4(ip) => write(&ip.octets()),
+        IpAddr::V6(ip) => write(&ip.octets()),
+    }
+    write(&[row.prefix.prefix()]);
+    for seg in row.as_path_segments.iter() {
+        write(&[u8::from(seg.seq), u8::from(seg.confed)]);
+        write(&(seg.as_path.len() as u32).to_be_bytes());
+        for asn in seg.as_path.iter() {
+            write(&asn.to_be_bytes());
+        }
+    }
+    // splitmix64 finaliser, FNV leaves the high bits of similar rows alike
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (hash ^ (hash >> 31)) as i64
 }
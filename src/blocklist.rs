@@ -0,0 +1,265 @@
+// Logs and Errors
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+
+// types
+use crate::intel::{Findings, ThreatIntel, Verdict};
+use crate::prefix_trie::{MatchKind, PrefixTrie};
+use ipnetwork::IpNetwork;
+use std::net::IpAddr;
+
+// bag of tools
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Listed on a blocklist someone curates, short of certain
+const CONFIDENCE: f64 = 0.9;
+
+/// Why an entry is listed, the list it came from and whatever note the list has for it
+#[derive(Debug, Clone)]
+struct Listing {
+    list: String,
+    note: Option<String>,
+}
+
+impl Listing {
+    fn reason(&self) -> String {
+        match &self.note {
+            Some(note) => format!("listed on {}: {note}", self.list),
+            None => format!("listed on {}", self.list),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Lists {
+    asns: HashMap<i64, Vec<Listing>>,
+    prefixes: PrefixTrie<Vec<Listing>>,
+}
+
+/// Local blocklists as a [`ThreatIntel`] provider, answers without any network access.
+///
+/// Every file under the given paths is read, directories one level deep. One entry per line, `;` or `#` start a
+/// comment, which covers Spamhaus DROP, EDROP and ASN-DROP (`1.10.16.0/20 ; SBL256894`, `AS9009 ; US | M247`),
+/// FireHOL netsets and plain lists of addresses, prefixes or `AS` numbers. Lines starting with `{` are JSON objects
+/// with a `cidr` or `asn`, as in the Spamhaus JSON feeds, and a file starting with `[` is a JSON array of those or
+/// of plain strings. The comment, or `sblid`, `asname` or `description` of a JSON entry, is kept as the reason.
+#[derive(Clone)]
+pub(crate) struct Blocklists {
+    paths: Vec<PathBuf>,
+    lists: Arc<RwLock<Lists>>,
+    matching: Arc<[MatchKind]>,
+}
+
+impl Blocklists {
+    /// Loads the lists at `paths` and reloads them every `refresh`, a list that fails to load keeps what
+    /// was loaded before. Announced prefixes are bad when they match a listed one in one of the `matching` kinds.
+    pub(crate) fn open(
+        paths: Vec<PathBuf>,
+        refresh: Duration,
+        matching: &[MatchKind],
+    ) -> Result<Self> {
+        if paths.is_empty() {
+            return Err(anyhow!("No blocklists given, use --blocklist"));
+        }
+        let blocklists = Blocklists {
+            lists: Arc::new(RwLock::new(load(&paths)?)),
+            paths,
+            matching: matching.into(),
+        };
+        let reloader = blocklists.clone();
+        tokio::spawn(async move {
+            let mut tick = tokio::time::interval(refresh);
+            tick.tick().await; // the first tick is immediate, the lists are fresh
+            loop {
+                tick.tick().await;
+                let paths = reloader.paths.clone();
+                match tokio::task::spawn_blocking(move || load(&paths)).await {
+                    Ok(Ok(lists)) => {
+                        *reloader.lists.write().unwrap_or_else(|e| e.into_inner()) = lists
+                    }
+                    Ok(Err(e)) => warn!("Could not reload blocklists, keeping the old ones, {e:#}"),
+                    Err(e) => warn!("Reloading blocklists panicked, {e}"),
+                }
+            }
+        });
+        Ok(blocklists)
+    }
+}
+
+impl ThreatIntel for Blocklists {
+    /// The ASN is bad when it is listed, a prefix when it matches a listed one
+    async fn lookup(&self, asn: i64, prefixes: &[IpNetwork]) -> Result<Findings> {
+        let lists = self.lists.read().unwrap_or_else(|e| e.into_inner());
+        let asn = lists.asns.get(&asn).map(|listings| Verdict {
+            malicious: true,
+            confidence: CONFIDENCE,
+            source: "blocklist".to_string(),
+            reasons: listings.iter().map(Listing::reason).collect(),
+            matches: vec![],
+        });
+        let mut found = BTreeMap::new();
+        for prefix in prefixes {
+            let matches = lists.prefixes.matches(prefix, &self.matching);
+            if matches.is_empty() {
+                continue;
+            }
+            let verdict = Verdict {
+                malicious: true,
+                confidence: CONFIDENCE,
+                source: "blocklist".to_string(),
+                reasons: matches
+                    .iter()
+                    .flat_map(|(m, listings)| {
+                        listings.iter().map(move |l| format!("{m}, {}", l.reason()))
+                    })
+                    .collect(),
+                matches: matches.into_iter().map(|(m, _)| m).collect(),
+            };
+            found.insert(*prefix, verdict);
+        }
+        Ok(Findings {
+            asn,
+            prefixes: found,
+            errors: vec![],
+        })
+    }
+}
+
+/// Reads every list under `paths`
+fn load(paths: &[PathBuf]) -> Result<Lists> {
+    let mut lists = Lists::default();
+    for path in paths {
+        let files = if path.is_dir() {
+            let mut files = std::fs::read_dir(path)
+                .with_context(|| format!("Failed while listing {}", path.display()))?
+                .map(|entry| Ok(entry?.path()))
+                .collect::<Result<Vec<_>>>()?;
+            files.retain(|f| f.is_file());
+            files.sort();
+            files
+        } else {
+            vec![path.clone()]
+        };
+        for file in files {
+            let (asns, prefixes) = load_file(&file, &mut lists)?;
+            debug!(
+                "Loaded {asns} ASNs and {prefixes} prefixes from {}",
+                file.display()
+            );
+        }
+    }
+    info!(
+        "Blocklists hold {} ASNs and {} prefixes",
+        lists.asns.len(),
+        lists.prefixes.len()
+    );
+    Ok(lists)
+}
+
+/// Adds the entries of one list to `lists`, returning how many ASNs and prefixes it had
+fn load_file(path: &Path, lists: &mut Lists) -> Result<(usize, usize)> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed while reading blocklist {}", path.display()))?;
+    let list = path
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let entries = if text.trim_start().starts_with('[') {
+        let values: Vec<serde_json::Value> = serde_json::from_str(&text)
+            .with_context(|| format!("Failed while parsing blocklist {}", path.display()))?;
+        values.iter().filter_map(json_entry).collect::<Vec<_>>()
+    } else {
+        let mut entries = vec![];
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            let entry = if line.starts_with('{') {
+                serde_json::from_str(line)
+                    .ok()
+                    .as_ref()
+                    .and_then(json_entry)
+            } else {
+                text_entry(line)
+            };
+            match entry {
+                Some(entry) => entries.push(entry),
+                None if is_blank(line) || line.starts_with('{') => {} // comments and JSON metadata
+                None => warn!("Skipping line {} of {}: {line}", n + 1, path.display()),
+            }
+        }
+        entries
+    };
+
+    let (mut asns, mut prefixes) = (0, 0);
+    for (entry, note) in entries {
+        let listing = Listing {
+            list: list.clone(),
+            note,
+        };
+        match entry {
+            Entry::Asn(asn) => {
+                asns += 1;
+                lists.asns.entry(asn).or_default().push(listing);
+            }
+            Entry::Prefix(prefix) => {
+                prefixes += 1;
+                lists.prefixes.entry(prefix).push(listing);
+            }
+        }
+    }
+    Ok((asns, prefixes))
+}
+
+enum Entry {
+    Asn(i64),
+    Prefix(IpNetwork),
+}
+
+fn is_blank(line: &str) -> bool {
+    line.is_empty() || line.starts_with(';') || line.starts_with('#')
+}
+
+/// `AS9009 ; note`, `1.10.16.0/20 ; note` or a bare address
+fn text_entry(line: &str) -> Option<(Entry, Option<String>)> {
+    let (value, note) = match line.find([';', '#']) {
+        Some(i) => (&line[..i], Some(line[i + 1..].trim().to_string())),
+        None => (line, None),
+    };
+    let value = value.split_whitespace().next()?;
+    Some((parse_value(value)?, note.filter(|n| !n.is_empty())))
+}
+
+fn json_entry(value: &serde_json::Value) -> Option<(Entry, Option<String>)> {
+    if let Some(value) = value.as_str() {
+        return Some((parse_value(value)?, None));
+    }
+    let entry = match (&value["asn"], &value["cidr"]) {
+        (serde_json::Value::Number(asn), _) => Entry::Asn(asn.as_i64()?),
+        (serde_json::Value::String(asn), _) => parse_value(asn)?,
+        (_, serde_json::Value::String(cidr)) => Entry::Prefix(IpNetwork::from_str(cidr).ok()?),
+        _ => return None,
+    };
+    let note = ["sblid", "asname", "description"]
+        .iter()
+        .find_map(|key| value[key].as_str())
+        .map(str::to_string);
+    Some((entry, note))
+}
+
+fn parse_value(value: &str) -> Option<Entry> {
+    let upper = value.to_ascii_uppercase();
+    if let Some(asn) = upper.strip_prefix("AS") {
+        return asn.parse().ok().map(Entry::Asn);
+    }
+    if let Ok(prefix) = IpNetwork::from_str(value) {
+        return Some(Entry::Prefix(prefix));
+    }
+    // bare addresses are host prefixes
+    IpAddr::from_str(value)
+        .ok()
+        .map(|ip| Entry::Prefix(IpNetwork::from(ip)))
+}
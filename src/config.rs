@@ -0,0 +1,394 @@
+// Logs and Errors
+use anyhow::{anyhow, Context, Result};
+use log::debug;
+
+// types
+use crate::intel::Source;
+use crate::pipeline::BatchSize;
+use crate::prefix_trie::MatchKind;
+use crate::storage::Store;
+
+// bag of tools
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// Read when no `--config` is given and it exists
+const DEFAULT_PATH: &str = "bgp_track.toml";
+
+/// Every setting, from the TOML config file, then the environment, then the command line, each one
+/// overriding the one before. Whatever none of them sets keeps its default, see `Job::Config show`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) collectors: Collectors,
+    pub(crate) detectors: Detectors,
+    pub(crate) database: Database,
+    pub(crate) writers: Writers,
+    pub(crate) intel: IntelSettings,
+    pub(crate) sinks: Sinks,
+}
+
+/// Where GetData gets its MRT files from
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Collectors {
+    /// `riperis` or `routeviews`
+    pub(crate) project: String,
+    pub(crate) collectors: Vec<String>,
+    /// Unix seconds
+    pub(crate) start: u64,
+    pub(crate) end: u64,
+    /// Files parsed at a time before waiting for the writers
+    pub(crate) chunk_size: usize,
+}
+
+impl Default for Collectors {
+    fn default() -> Self {
+        Collectors {
+            project: "riperis".to_string(),
+            collectors: vec!["rrc25".to_string()],
+            start: 1_660_751_400,
+            end: 1_660_773_600,
+            chunk_size: 12,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Detectors {
+    pub(crate) short_lived: ShortLived,
+}
+
+/// What FindShortLived counts as short lived and where it looks
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ShortLived {
+    /// Longest an announcement may live before its withdrawal, in seconds
+    pub(crate) max_lifetime: i64,
+    /// Unix seconds
+    pub(crate) start: i64,
+    pub(crate) end: i64,
+    /// Potential hijacks per chunk, all of them when unset
+    pub(crate) limit: Option<i64>,
+    /// Seconds of announcements queried at a time
+    pub(crate) chunk: i64,
+}
+
+impl Default for ShortLived {
+    fn default() -> Self {
+        ShortLived {
+            max_lifetime: 900,
+            start: 1_660_687_200,
+            end: 1_660_694_499,
+            limit: None,
+            chunk: 3600,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Database {
+    pub(crate) store: Store,
+    /// Postgres connection string, `DATABASE_URL` or `--database-url` override it
+    pub(crate) url: Option<String>,
+    /// Directory of the files store
+    pub(crate) data_dir: PathBuf,
+    /// Database file of the sqlite store
+    pub(crate) sqlite_path: PathBuf,
+    /// Apply pending migrations to postgres before running
+    pub(crate) migrate: bool,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Database {
+            store: Store::Postgres,
+            url: None,
+            data_dir: PathBuf::from("data"),
+            sqlite_path: PathBuf::from("bgp_track.sqlite"),
+            migrate: false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Writers {
+    /// Writer tasks copying in parallel, each over a connection of its own
+    pub(crate) count: usize,
+    /// Rows held by the parsers and writers at once before the parsers wait, in MB
+    pub(crate) memory_limit_mb: usize,
+    /// Rows a parser hands to its writer at a time, batches are smaller when the memory limit asks for it
+    pub(crate) batch_rows: usize,
+}
+
+impl Default for Writers {
+    fn default() -> Self {
+        Writers {
+            count: 4,
+            memory_limit_mb: 512,
+            batch_rows: 10_000,
+        }
+    }
+}
+
+impl Writers {
+    pub(crate) fn memory_bytes(&self) -> usize {
+        self.memory_limit_mb * 1_000_000
+    }
+
+    /// Every parser holds a batch and waits with another for its writer, which holds a third.
+    /// Sizing them to a quarter of the limit per parser thread leaves room for the live feeds.
+    pub(crate) fn batch_size(&self) -> BatchSize {
+        BatchSize {
+            rows: self.batch_rows.max(1),
+            bytes: self.memory_bytes() / (4 * rayon::current_num_threads()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct IntelSettings {
+    /// Threat intel providers asked by FindShortLived
+    pub(crate) providers: Vec<Source>,
+    /// Blocklist files or directories of them for the blocklist provider
+    pub(crate) blocklists: Vec<PathBuf>,
+    pub(crate) blocklist_refresh_mins: u64,
+    /// Directory caching the raw threat intel API responses
+    pub(crate) cache_dir: PathBuf,
+    pub(crate) cache_ttl_hours: u64,
+    /// Only answer from the cache, never call the APIs
+    pub(crate) offline: bool,
+    /// Call the APIs for everything and replace what is cached
+    pub(crate) refresh: bool,
+    /// Batches of ASNs looked up at the same time
+    pub(crate) concurrency: usize,
+    /// ASNs looked up together, their announced prefixes with them
+    pub(crate) batch_size: usize,
+    /// How an announced prefix has to match a known bad one to count
+    pub(crate) prefix_match: Vec<MatchKind>,
+    pub(crate) seclytics: SeclyticsSettings,
+}
+
+impl Default for IntelSettings {
+    fn default() -> Self {
+        IntelSettings {
+            providers: vec![Source::Seclytics],
+            blocklists: vec![],
+            blocklist_refresh_mins: 60,
+            cache_dir: PathBuf::from("intel_cache"),
+            cache_ttl_hours: 24,
+            offline: false,
+            refresh: false,
+            concurrency: 8,
+            batch_size: 100,
+            prefix_match: vec![MatchKind::Exact, MatchKind::CoveredBy, MatchKind::Covers],
+            seclytics: SeclyticsSettings::default(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SeclyticsSettings {
+    /// `SECLYTICS_API_ENDPOINT` or `--seclytics-endpoint` override it
+    pub(crate) endpoint: Option<String>,
+    /// `SECLYTICS_API_TOKEN` or `--seclytics-token` override it
+    pub(crate) token: Option<String>,
+    /// Requests per second, 0 for no limit
+    pub(crate) rps: f64,
+    /// Retries with exponential backoff when Seclytics is overloaded or unreachable
+    pub(crate) retries: u32,
+    /// Ids asked for per request to the bulk endpoints
+    pub(crate) batch_size: usize,
+}
+
+impl Default for SeclyticsSettings {
+    fn default() -> Self {
+        SeclyticsSettings {
+            endpoint: None,
+            token: None,
+            rps: 5.0,
+            retries: 4,
+            batch_size: 100,
+        }
+    }
+}
+
+/// Where the logs go
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Sinks {
+    /// Appended to, no log file when unset
+    pub(crate) log_file: Option<PathBuf>,
+    pub(crate) stdout: bool,
+}
+
+impl Default for Sinks {
+    fn default() -> Self {
+        Sinks {
+            log_file: Some(PathBuf::from("output.log")),
+            stdout: true,
+        }
+    }
+}
+
+/// The parts of the config a job uses, only those are checked before it runs
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct Needs {
+    /// `collectors`, to load MRT files
+    pub(crate) collectors: bool,
+    /// `detectors.short_lived`
+    pub(crate) short_lived: bool,
+    /// `database` and `writers`, to read or write announcements
+    pub(crate) store: bool,
+    /// `intel`, to ask the threat intel providers
+    pub(crate) intel: bool,
+}
+
+impl Needs {
+    pub(crate) const ALL: Needs = Needs {
+        collectors: true,
+        short_lived: true,
+        store: true,
+        intel: true,
+    };
+}
+
+impl Config {
+    /// Reads `path`, or `bgp_track.toml` when there is one, the environment and flags go over it later
+    pub(crate) fn load(path: Option<&Path>) -> Result<Self> {
+        let path = match path {
+            Some(path) => Some(path),
+            None => Some(Path::new(DEFAULT_PATH)).filter(|p| p.exists()),
+        };
+        let config = match path {
+            Some(path) => {
+                let text = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed while reading config {}", path.display()))?;
+                toml::from_str(&text)
+                    .with_context(|| format!("Failed while parsing config {}", path.display()))?
+            }
+            None => Config::default(),
+        };
+        Ok(config)
+    }
+
+    /// Every value that can not work in what a job `needs`, all at once
+    pub(crate) fn validate(&self, needs: Needs) -> Result<()> {
+        let mut problems = vec![];
+        let c = &self.collectors;
+        if needs.collectors && c.collectors.is_empty() {
+            problems.push("collectors.collectors lists no collector".to_string());
+        }
+        if needs.collectors && c.start >= c.end {
+            problems.push(format!("collectors.start {} is not before end {}", c.start, c.end));
+        }
+        if needs.collectors && c.chunk_size == 0 {
+            problems.push("collectors.chunk_size is 0".to_string());
+        }
+        if needs.short_lived {
+            self.short_lived_problems(&mut problems);
+        }
+        if needs.store && self.writers.count == 0 {
+            problems.push("writers.count is 0".to_string());
+        }
+        if needs.intel {
+            self.intel_problems(&mut problems);
+        }
+        if problems.is_empty() {
+            debug!("Config is valid");
+            return Ok(());
+        }
+        Err(anyhow!("Invalid config:\n  - {}", problems.join("\n  - ")))
+    }
+
+    fn short_lived_problems(&self, problems: &mut Vec<String>) {
+        let s = &self.detectors.short_lived;
+        if s.start >= s.end {
+            problems.push(format!(
+                "detectors.short_lived.start {} is not before end {}",
+                s.start, s.end
+            ));
+        }
+        if s.max_lifetime <= 0 {
+            problems.push("detectors.short_lived.max_lifetime is not positive".to_string());
+        }
+        if s.chunk <= 0 {
+            problems.push("detectors.short_lived.chunk is not positive".to_string());
+        }
+        if s.limit.is_some_and(|l| l <= 0) {
+            problems.push("detectors.short_lived.limit is not positive".to_string());
+        }
+    }
+
+    fn intel_problems(&self, problems: &mut Vec<String>) {
+        let i = &self.intel;
+        if i.providers.is_empty() {
+            problems.push("intel.providers lists no provider".to_string());
+        }
+        for path in i.blocklists.iter().filter(|p| !p.exists()) {
+            problems.push(format!("intel.blocklists has {}, which does not exist", path.display()));
+        }
+        if i.offline && i.refresh {
+            problems.push("intel.offline and intel.refresh are both set".to_string());
+        }
+        if i.prefix_match.is_empty() {
+            problems.push("intel.prefix_match lists no kind of match".to_string());
+        }
+        if i.seclytics.rps < 0.0 {
+            problems.push("intel.seclytics.rps is negative".to_string());
+        }
+    }
+
+    /// What a job that `needs` store or intel can not do without. Everything else may stay unset.
+    pub(crate) fn require(&self, needs: Needs) -> Result<()> {
+        let (store, intel) = (needs.store, needs.intel);
+        let mut missing = vec![];
+        if store && self.database.store == Store::Postgres && self.database.url.is_none() {
+            missing.push("a postgres to connect to, set DATABASE_URL, --database-url or database.url");
+        }
+        let i = &self.intel;
+        if intel && i.providers.contains(&Source::Seclytics) && !i.offline {
+            if i.seclytics.endpoint.is_none() {
+                missing.push(
+                    "the Seclytics endpoint, set SECLYTICS_API_ENDPOINT, --seclytics-endpoint or \
+                     intel.seclytics.endpoint, or use --offline",
+                );
+            }
+            if i.seclytics.token.is_none() {
+                missing.push(
+                    "a Seclytics api token, set SECLYTICS_API_TOKEN, --seclytics-token or \
+                     intel.seclytics.token, or use --offline",
+                );
+            }
+        }
+        if intel && i.providers.contains(&Source::Blocklist) && i.blocklists.is_empty() {
+            missing.push("blocklists for the blocklist provider, use --blocklist or intel.blocklists");
+        }
+        if missing.is_empty() {
+            return Ok(());
+        }
+        Err(anyhow!("This job needs:\n  - {}", missing.join("\n  - ")))
+    }
+
+    /// The config as TOML, without the database password or api token
+    pub(crate) fn show(&self) -> Result<String> {
+        let mut config = self.clone();
+        if config.intel.seclytics.token.is_some() {
+            config.intel.seclytics.token = Some("***".to_string());
+        }
+        if let Some(url) = &mut config.database.url {
+            if let Ok(mut parsed) = url::Url::parse(url) {
+                if parsed.password().is_some() {
+                    let _ = parsed.set_password(Some("***"));
+                    *url = parsed.to_string();
+                }
+            }
+        }
+        Ok(toml::to_string_pretty(&config)?)
+    }
+}
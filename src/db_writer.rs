@@ -1,43 +1,122 @@
 pub(crate) mod types {
     // Import Special Types
     use ipnetwork::IpNetwork;
-    pub(crate) type UnixTimeStamp = i32;
+    use time::OffsetDateTime;
+    /// Whole seconds since the epoch, 64 bit so it outlives 2038
+    pub(crate) type UnixTimeStamp = i64;
 
     // sqlx stuff
     use serde::{Deserialize, Serialize};
     use sqlx::postgres::{PgHasArrayType, PgTypeInfo};
-    pub(crate) static DELIMITER: &str = ","; // helps keep delimiter constant between copy command and display impl
-    use std::{fmt, ops}; // a bunch of hacky stuff
+
+    /// Signature, flags and header extension length opening a `COPY ... (FORMAT binary)` stream
+    pub(crate) const COPY_HEADER: &[u8] = b"PGCOPY\n\xff\r\n\0\0\0\0\0\0\0\0\0";
+    /// Tuple field count of -1, closing the stream
+    pub(crate) const COPY_TRAILER: &[u8] = &(-1i16).to_be_bytes();
+    const BOOL_OID: u32 = 16;
+    const INT8_OID: u32 = 20;
+    const INT8_ARRAY_OID: u32 = 1016;
+    /// Microseconds between the unix and the Postgres epoch, 2000-01-01
+    const PG_EPOCH_MICROS: i64 = 946_684_800_000_000;
 
     #[allow(dead_code)]
     #[derive(sqlx::FromRow, Debug, Clone, PartialEq, PartialOrd)]
     pub(crate) struct Announcement {
-        pub(crate) id: uuid::Uuid,
+        pub(crate) id: i64,
         pub(crate) asn: i64,
         pub(crate) withdrawal: bool,
-        pub(crate) timestamp: f64,
+        pub(crate) timestamp: OffsetDateTime,
         pub(crate) prefix: IpNetwork,
         pub(crate) as_path_segments: Vec<ASPathSeg>,
     }
-    impl ops::Deref for APSegments {
-        type Target = Vec<ASPathSeg>;
+    impl Announcement {
+        /// One tuple for `COPY Announcement FROM STDIN (FORMAT binary)`, between [`COPY_HEADER`] and
+        /// [`COPY_TRAILER`]. `segment_oid` is the oid of `as_path_segment`, it differs between databases.
+        pub(crate) fn write_binary(&self, buf: &mut Vec<u8>, segment_oid: u32) {
+            buf.extend_from_slice(&6i16.to_be_bytes());
+            field(buf, &self.id.to_be_bytes());
+            field(buf, &self.asn.to_be_bytes());
+            field(buf, &[u8::from(self.withdrawal)]);
+            field(
+                buf,
+                &(to_micros(self.timestamp) - PG_EPOCH_MICROS).to_be_bytes(),
+            );
+            field(buf, &inet(self.prefix));
 
-        fn deref(&self) -> &Self::Target {
-            &self.0
+            let mut segments = vec![];
+            for seg in self.as_path_segments.iter() {
+                let mut record = vec![];
+                record.extend_from_slice(&3i32.to_be_bytes());
+                column(&mut record, BOOL_OID, &[u8::from(seg.seq)]);
+                column(&mut record, BOOL_OID, &[u8::from(seg.confed)]);
+                let mut path = vec![];
+                for asn in seg.as_path.iter() {
+                    field(&mut path, &asn.to_be_bytes());
+                }
+                column(
+                    &mut record,
+                    INT8_ARRAY_OID,
+                    &array(INT8_OID, seg.as_path.len(), &path),
+                );
+                field(&mut segments, &record);
+            }
+            field(
+                buf,
+                &array(segment_oid, self.as_path_segments.len(), &segments),
+            );
         }
     }
-    pub(crate) struct APSegments(pub(crate) Vec<ASPathSeg>);
-    impl fmt::Display for APSegments {
-        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-            write!(
-                f,
-                "\"{{{}}}\"",
-                self.iter()
-                    .map(|x| format!("{}", x))
-                    .collect::<Vec<String>>()
-                    .join(DELIMITER)
-            )
+
+    /// Microseconds since the epoch, the precision `timestamptz` keeps
+    pub(crate) fn to_micros(t: OffsetDateTime) -> i64 {
+        (t.unix_timestamp_nanos() / 1_000) as i64
+    }
+
+    pub(crate) fn from_micros(micros: i64) -> Result<OffsetDateTime, time::error::ComponentRange> {
+        OffsetDateTime::from_unix_timestamp_nanos(i128::from(micros) * 1_000)
+    }
+
+    /// Fractional seconds as the MRT parser reports them, rounded to the microsecond
+    pub(crate) fn from_secs_f64(secs: f64) -> OffsetDateTime {
+        from_micros((secs * 1e6).round() as i64).unwrap_or(OffsetDateTime::UNIX_EPOCH)
+    }
+
+    /// Length prefixed value
+    fn field(buf: &mut Vec<u8>, value: &[u8]) {
+        buf.extend_from_slice(&(value.len() as i32).to_be_bytes());
+        buf.extend_from_slice(value);
+    }
+
+    /// Composite column, type oid then the length prefixed value
+    fn column(buf: &mut Vec<u8>, oid: u32, value: &[u8]) {
+        buf.extend_from_slice(&oid.to_be_bytes());
+        field(buf, value);
+    }
+
+    /// One dimensional array without nulls of `len` already length prefixed `elements`
+    fn array(oid: u32, len: usize, elements: &[u8]) -> Vec<u8> {
+        let mut buf = vec![];
+        let ndim: i32 = if len == 0 { 0 } else { 1 };
+        buf.extend_from_slice(&ndim.to_be_bytes());
+        buf.extend_from_slice(&0i32.to_be_bytes()); // has nulls
+        buf.extend_from_slice(&oid.to_be_bytes());
+        if len > 0 {
+            buf.extend_from_slice(&(len as i32).to_be_bytes());
+            buf.extend_from_slice(&1i32.to_be_bytes()); // lower bound
         }
+        buf.extend_from_slice(elements);
+        buf
+    }
+
+    /// Family, prefix length, is cidr, address length and address, as in `inet_recv`
+    fn inet(net: IpNetwork) -> Vec<u8> {
+        let (family, addr) = match net {
+            IpNetwork::V4(n) => (2u8, n.ip().octets().to_vec()),
+            IpNetwork::V6(n) => (3u8, n.ip().octets().to_vec()),
+        };
+        let mut buf = vec![family, net.prefix(), 0, addr.len() as u8];
+        buf.extend_from_slice(&addr);
+        buf
     }
 
     #[derive(sqlx::Type, Debug, Serialize, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord)]
@@ -53,26 +132,16 @@ pub(crate) mod types {
             PgTypeInfo::with_name("_as_path_segment")
         }
     }
-    impl fmt::Display for ASPathSeg {
-        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-            let tmp = self
-                .as_path
-                .iter()
-                .map(|x| x.to_string())
-                .collect::<Vec<String>>()
-                .join(&*("\\".to_string() + DELIMITER));
-            write!(
-                f,
-                "({}\\{DELIMITER}{}\\{DELIMITER}\\\"\"\\{{{}\\}}\\\"\")",
-                self.seq, self.confed, tmp
-            )
-        }
-    }
 }
 
 // types
-use crate::db_writer::types::{ASPathSeg, Announcement, UnixTimeStamp};
+use crate::bgp::FileReport;
+use crate::db_writer::types::{ASPathSeg, Announcement, UnixTimeStamp, COPY_HEADER, COPY_TRAILER};
+use crate::pipeline::{FileBatch, FileStream};
+use crate::staging;
+use crate::storage::Storage;
 use ipnetwork::IpNetwork;
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::net::IpAddr;
 use time::OffsetDateTime;
 
@@ -81,99 +150,393 @@ use anyhow::{anyhow, Context, Result};
 
 use async_stream::try_stream;
 use futures::Stream;
-use itertools::Itertools;
-use lazy_static::lazy_static;
-use log::{debug, info, warn};
-
-lazy_static! {
-    pub(crate) static ref PG_URL: String = {
-        use dotenvy::dotenv;
-        dotenv().expect(".env file not found");
-        dotenvy::var("DATABASE_URL").expect("DATABASE_URL not found")
-    };
+use log::{debug, info};
+
+/// Width of a partition of `Announcement` and `Announcement_new`
+const DAY: i64 = 86_400;
+
+fn to_datetime(timestamp: UnixTimeStamp) -> Result<OffsetDateTime> {
+    Ok(OffsetDateTime::from_unix_timestamp(timestamp)?)
 }
 
-pub(crate) async fn open_db() -> Result<sqlx::PgPool, anyhow::Error> {
+/// Connects to `url` with a connection for each of the `writers` and a few for everything else,
+/// bringing the schema up to date first when `migrate` is set
+pub(crate) async fn open_db(url: &str, migrate: bool, writers: usize) -> Result<sqlx::PgPool, anyhow::Error> {
     debug!("Spinning up db conn...");
 
-    let pool = sqlx::postgres::PgPool::connect(&**PG_URL)
+    let pool = sqlx::postgres::PgPoolOptions::new()
+        .max_connections(writers as u32 + 4)
+        .connect(url)
         .await
         .context("Failed while connecting to pg db")?;
 
-    //warn!("Running migrations...");
-    /*
-       sqlx::migrate!("./migrations")
-           .run(&pool)
-           .await
-           .context("Failed while migrating")?;
-    */
+    if migrate {
+        crate::migrate::up(&pool).await?;
+    }
     Ok(pool)
 }
 
-/// Given a PG database Pool [`sqlx::PgPool`], drops all AS (Oranges)
-/// WARNING: GIVES NO WARNING BEFORE DELETING ALL DATA
-///
-/// # Examples
-/// ```
-/// delete_all(&pool).await? // Bye bye data
-/// `
-#[allow(unreachable_code, unused)]
-pub(crate) async fn delete_all(pool: &sqlx::PgPool) -> Result<(), sqlx::Error> {
-    panic!("GO AWAY, ITS HAPPENED TOO MANY TIMES");
+impl Storage for sqlx::PgPool {
+    /// Partially parsed files are included, their corrupt records would only be skipped again
+    async fn ingested_files(&self) -> Result<HashSet<String>> {
+        Ok(sqlx::query_scalar!(
+            "SELECT url FROM ingested_file WHERE status IN ('loaded', 'partial')"
+        )
+        .fetch_all(self)
+        .await?
+        .into_iter()
+        .collect())
+    }
+
+    /// Copies the file's batches into a temporary table as they arrive, so a long file holds no lock on
+    /// `Announcement_new`. Once it is parsed the rows are moved into `Announcement_new` in the same transaction
+    /// as the rollups, the `ingested_file` checkpoint and the row counts [`staging::promote`] validates.
+    /// A failed or restarted file leaves nothing behind.
+    async fn write_file(
+        &self,
+        collector: &str,
+        mut batches: FileStream,
+    ) -> Result<(FileReport, u64)> {
+        let segment_oid = segment_oid(self).await?;
+        let mut tx = self.begin().await?;
+        create_file_rows(&mut tx).await?;
+        let mut days = BTreeSet::new();
+        let mut checksum = crc32fast::Hasher::new();
+        loop {
+            match batches.next() {
+                Some(FileBatch::Rows(rows)) => {
+                    copy_file_rows(&mut tx, &rows, segment_oid, &mut days, &mut checksum).await?
+                }
+                Some(FileBatch::Restart) => {
+                    sqlx::query("TRUNCATE file_rows").execute(&mut *tx).await?;
+                    days.clear();
+                    checksum = crc32fast::Hasher::new();
+                }
+                // dropping the transaction rolls it back
+                Some(FileBatch::Done(report)) if report.error.is_some() => return Ok((report, 0)),
+                Some(FileBatch::Done(report)) => {
+                    let rows = stage_file_rows(self, &mut tx, collector, days).await?;
+                    sqlx::query!(
+                        r#"
+INSERT INTO ingested_file (url, status, row_count, checksum, corrupt_records)
+VALUES ($1, $2, $3, $4, $5)
+ON CONFLICT (url) DO UPDATE SET status          = EXCLUDED.status,
+                                row_count       = EXCLUDED.row_count,
+                                checksum        = EXCLUDED.checksum,
+                                corrupt_records = EXCLUDED.corrupt_records,
+                                error           = NULL,
+                                ingested_at     = now()
+"#,
+                        report.url,
+                        report.status(),
+                        rows as i64,
+                        i64::from(checksum.finalize()),
+                        report.corrupt_records as i64
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                    tx.commit().await?;
+                    return Ok((report, rows));
+                }
+                None => return Err(anyhow!("The parser went away before the end of the file")),
+            }
+        }
+    }
+
+    /// Copies live rows into `Announcement_new` in a transaction of their own, see [`Storage::write_file`]
+    async fn write_batch(&self, collector: &str, rows: Vec<Announcement>) -> Result<u64> {
+        let segment_oid = segment_oid(self).await?;
+        let mut tx = self.begin().await?;
+        create_file_rows(&mut tx).await?;
+        let mut days = BTreeSet::new();
+        let mut checksum = crc32fast::Hasher::new();
+        copy_file_rows(&mut tx, &rows, segment_oid, &mut days, &mut checksum).await?;
+        let rows = stage_file_rows(self, &mut tx, collector, days).await?;
+        tx.commit().await?;
+        Ok(rows)
+    }
+
+    async fn record_failure(&self, report: &FileReport) -> Result<()> {
+        sqlx::query!(
+            r#"
+INSERT INTO ingested_file (url, status, row_count, checksum, corrupt_records, error)
+VALUES ($1, $2, 0, 0, $3, $4)
+ON CONFLICT (url) DO UPDATE SET status          = EXCLUDED.status,
+                                corrupt_records = EXCLUDED.corrupt_records,
+                                error           = EXCLUDED.error,
+                                ingested_at     = now()
+"#,
+            report.url,
+            report.status(),
+            report.corrupt_records as i64,
+            report.error
+        )
+        .execute(self)
+        .await?;
+        Ok(())
+    }
+
+    /// Adds the primary key and indexes of every partition of `Announcement_new`, they survive from earlier runs
+    /// so only partitions created by this run take long. The staged partitions are then promoted into `Announcement`.
+    async fn finish_ingest(&self) -> Result<()> {
+        use std::time::Instant;
+        let partitions = sqlx::query_scalar!(
+            r#"SELECT inhrelid::regclass::text AS "partition!" FROM pg_inherits WHERE inhparent = 'announcement_new'::regclass"#
+        )
+        .fetch_all(self)
+        .await?;
+
+        for partition in partitions {
+            let now = Instant::now();
+            sqlx::query!("SELECT index_announcement_partition($1)", partition)
+                .execute(self)
+                .await?;
+            info!(">>> Indexed {partition} in: {:.2?}", now.elapsed());
+        }
+        staging::promote(self).await
+    }
+
+    /// Drops the day partitions of `Announcement` and `Announcement_new` that ended before `before`
+    async fn prune(&self, before: UnixTimeStamp) -> Result<()> {
+        staging::prune(self, before).await
+    }
+
+    async fn short_lived_window(
+        &self,
+        window: UnixTimeStamp,
+        start: UnixTimeStamp,
+        stop: UnixTimeStamp,
+        limit: Option<i64>,
+        filter: &ShortLivedFilter,
+    ) -> Result<Vec<PotentialHijack>> {
+        PotentialHijack::query_short_lived_window(window, start, stop, limit, filter, self).await
+    }
+
+    /// Given a ip address [`IpAddr`], finds if any announcements relating to that ip
+    /// # Examples
+    /// ```
+    /// pool.ip_search("1.0.0.1".parse()?).await? // Any announcements for a prefix containing 1.0.0.1?
+    /// ```
+    async fn ip_search(&self, ip: IpAddr) -> Result<Vec<Announcement>> {
+        let res = sqlx::query_as!(
+            Announcement,
+            r#"SELECT id, asn, withdrawal, timestamp, prefix, as_path_segments as "as_path_segments: Vec<ASPathSeg>" FROM Announcement as a WHERE (a.prefix >> $1) AND a.withdrawal = false;"#,
+            IpNetwork::from(ip)
+        )
+            .fetch_all(self)
+            .await?;
+        Ok(res)
+    }
+}
+
+/// Oid of the `as_path_segment` type, the binary COPY format names it
+async fn segment_oid(pool: &sqlx::PgPool) -> Result<u32> {
+    static SEGMENT_OID: tokio::sync::OnceCell<u32> = tokio::sync::OnceCell::const_new();
+    Ok(*SEGMENT_OID
+        .get_or_try_init(|| async {
+            let oid = sqlx::query_scalar!(r#"SELECT 'as_path_segment'::regtype::oid AS "oid!""#)
+                .fetch_one(pool)
+                .await?;
+            anyhow::Ok(oid.0)
+        })
+        .await?)
+}
 
-    warn!("Truncating Announcement Table");
+/// Temporary table the rows of a file are copied into while it is parsed, dropped with the transaction.
+/// It is spelled out rather than `LIKE announcement_new`, that would lock `Announcement_new` for as long.
+async fn create_file_rows(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>) -> Result<()> {
+    sqlx::query(
+        r#"
+CREATE TEMP TABLE file_rows
+(
+    id               bigint            not null,
+    asn              bigint            not null,
+    withdrawal       boolean           not null,
+    timestamp        timestamptz       not null,
+    prefix           inet              not null,
+    as_path_segments as_path_segment[] not null
+) ON COMMIT DROP
+"#,
+    )
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
+
+/// Binary copies `rows` into `file_rows`, noting the days they fall on in `days`
+async fn copy_file_rows(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    rows: &[Announcement],
+    segment_oid: u32,
+    days: &mut BTreeSet<i64>,
+    checksum: &mut crc32fast::Hasher,
+) -> Result<()> {
+    let mut data = COPY_HEADER.to_vec();
+    for row in rows.iter() {
+        days.insert(row.timestamp.unix_timestamp().div_euclid(DAY) * DAY);
+        row.write_binary(&mut data, segment_oid);
+    }
+    data.extend_from_slice(COPY_TRAILER);
+    checksum.update(&data);
 
-    sqlx::query("TRUNCATE Announcement").execute(pool).await?;
+    let mut cpin = tx
+        .copy_in_raw("COPY file_rows FROM STDIN (FORMAT binary)")
+        .await?;
+    cpin.send(data).await?;
+    cpin.finish().await?;
     Ok(())
 }
 
-/// Given a ip address [`IpAddr`] and a PG database Pool [`sqlx::PgPool`], finds if any announcements relating to that ip
-/// # Examples
-/// ```
-/// search("1.0.0.1".parse()?, &pool).await? // Any announcements for a prefix containing 1.0.0.1?
-/// ```
-pub(crate) async fn ip_search(
-    ip: IpAddr,
+/// Moves `file_rows` into `Announcement_new` and adds them to the rollups and `staged_partition`.
+/// Rows already loaded into either table, or twice in the file, are dropped first so a file loaded again
+/// is neither stored nor counted twice.
+/// The day partitions are created over `pool` first, the transaction holds no lock on `Announcement_new` until
+/// the rows move, so creating one never waits on a transaction that waits on it. A promotion waits for the
+/// transaction, so it never detaches a partition between its creation and the rows moving into it.
+async fn stage_file_rows(
     pool: &sqlx::PgPool,
-) -> Result<Vec<Announcement>, sqlx::Error> {
-    let res = sqlx::query_as!(
-        Announcement,
-        r#"SELECT id, asn, withdrawal, timestamp, prefix, as_path_segments as "as_path_segments: Vec<ASPathSeg>" FROM Announcement as a WHERE (a.prefix >> $1) AND a.withdrawal = false;"#,
-        IpNetwork::from(ip)
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    collector: &str,
+    days: BTreeSet<i64>,
+) -> Result<u64> {
+    staging::hold_off(tx).await?;
+    let mut partitions = HashMap::with_capacity(days.len());
+    for day in days {
+        let partition = sqlx::query_scalar!(
+            r#"SELECT announcement_partition('announcement_new', $1) AS "partition!""#,
+            to_datetime(day)?
+        )
+        .fetch_one(pool)
+        .await?;
+        partitions.insert(day, partition);
+    }
+
+    sqlx::query(
+        r#"
+DELETE
+FROM file_rows AS f
+    USING file_rows AS g
+WHERE f.timestamp = g.timestamp
+  AND f.id = g.id
+  AND f.ctid > g.ctid
+"#,
+    )
+    .execute(&mut **tx)
+    .await?;
+    sqlx::query(
+        r#"
+DELETE
+FROM file_rows AS f
+WHERE EXISTS (SELECT FROM announcement AS a WHERE a.timestamp = f.timestamp AND a.id = f.id)
+   OR EXISTS (SELECT FROM announcement_new AS a WHERE a.timestamp = f.timestamp AND a.id = f.id)
+"#,
     )
-        .fetch_all(pool)
+    .execute(&mut **tx)
+    .await?;
+
+    // a concurrent load of the same rows is the only conflict left
+    let counts: Vec<(i64, i64)> = sqlx::query_as(&format!(
+        r#"
+WITH inserted AS (
+    INSERT INTO Announcement_new SELECT * FROM file_rows ON CONFLICT DO NOTHING RETURNING timestamp)
+SELECT (floor(extract(EPOCH FROM timestamp) / {DAY}) * {DAY})::bigint, count(*)
+FROM inserted
+GROUP BY 1
+"#
+    ))
+    .fetch_all(&mut **tx)
+    .await?;
+    add_rollup(tx, collector).await?;
+    let mut rows = 0;
+    for (day, count) in counts {
+        let partition = partitions
+            .get(&day)
+            .ok_or(anyhow!("No partition was created for day {day}"))?;
+        sqlx::query!(
+            r#"
+INSERT INTO staged_partition (partition, day, row_count)
+VALUES ($1, $2, $3)
+ON CONFLICT (partition) DO UPDATE SET row_count = staged_partition.row_count + EXCLUDED.row_count
+"#,
+            partition,
+            to_datetime(day)?,
+            count
+        )
+        .execute(&mut **tx)
         .await?;
-    Ok(res)
+        rows += count as u64;
+    }
+    Ok(rows)
+}
+
+/// Adds the counts of `file_rows` to the staged rollups, see [`Rollup`](crate::rollup::Rollup).
+/// Rows are upserted in key order so concurrent writers do not deadlock on them.
+async fn add_rollup(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, collector: &str) -> Result<()> {
+    for (table, bucket, width) in [
+        ("prefix_hourly", "hour", 3600),
+        ("prefix_daily", "day", DAY),
+    ] {
+        sqlx::query(&format!(
+            r#"
+INSERT INTO {table} ({bucket}, prefix, origin, announcements, withdrawals)
+SELECT to_timestamp(floor(extract(EPOCH FROM timestamp) / {width}) * {width}),
+       prefix,
+       coalesce((last).as_path[cardinality((last).as_path)], 0),
+       count(*) FILTER (WHERE NOT withdrawal),
+       count(*) FILTER (WHERE withdrawal)
+FROM (SELECT timestamp, prefix, withdrawal, as_path_segments[cardinality(as_path_segments)] AS last
+      FROM file_rows) AS r
+GROUP BY 1, 2, 3
+ORDER BY 1, 2, 3
+ON CONFLICT ({bucket}, prefix, origin, promotion) DO UPDATE SET announcements = {table}.announcements + EXCLUDED.announcements,
+                                                     withdrawals   = {table}.withdrawals + EXCLUDED.withdrawals
+"#
+        ))
+        .execute(&mut **tx)
+        .await?;
+    }
+
+    sqlx::query(
+        r#"
+INSERT INTO peer_hourly (hour, collector, peer_asn, announcements, withdrawals)
+SELECT to_timestamp(floor(extract(EPOCH FROM timestamp) / 3600) * 3600), $1, asn,
+       count(*) FILTER (WHERE NOT withdrawal),
+       count(*) FILTER (WHERE withdrawal)
+FROM file_rows
+GROUP BY 1, 3
+ORDER BY 1, 3
+ON CONFLICT (hour, collector, peer_asn, promotion) DO UPDATE SET announcements = peer_hourly.announcements + EXCLUDED.announcements,
+                                                      withdrawals   = peer_hourly.withdrawals + EXCLUDED.withdrawals
+"#,
+    )
+    .bind(collector)
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
 }
 
 /// Collects all short lived announcements and runs a [`Processor`] on them, returning the results
 /// MAKE SURE TO PIN FOR USE
 /// pin_mut!(n);
-pub(crate) async fn find_short_lived(
+pub(crate) async fn find_short_lived<S: Storage>(
     window: UnixTimeStamp,
     start: UnixTimeStamp,
     stop: UnixTimeStamp,
     limit: Option<i64>,
-    yield_window: i32,
+    yield_window: UnixTimeStamp,
+    filter: ShortLivedFilter,
     // processor: Processor,
-    pool: &sqlx::PgPool,
+    store: &S,
 ) -> impl Stream<Item = Result<PotentialHijack /*(IpNetwork, OffsetDateTime, OffsetDateTime, i64)*/>> + '_
 {
     try_stream! {
         debug!("Start: {start}, Stop: {stop}, chunk size: {}", yield_window as usize);
-        for mut i in &((start+1)..stop).chunks(yield_window as usize) {
-            // debug!("Chunk: {i:?}");
-            let sub_start = i.next();
-            let mut sub_stop = i.last();
+        for sub_start in (start..stop).step_by(yield_window.max(1) as usize) {
+            let sub_stop = (sub_start + yield_window).min(stop);
 
-            debug!("Short lived sub-query window is now between {:?} and {:?}", sub_start, sub_stop );
+            debug!("Short lived sub-query window is now between {sub_start} and {sub_stop}");
 
-            // rare edge case where the last chunk is exactly 1 sec and thus the iter only has one value to yield
-            if sub_start.is_some() && sub_stop.is_none() {
-                sub_stop = sub_start
-            }
-
-            for potential in PotentialHijack::query_short_lived_window(window, (sub_start.ok_or(anyhow!("No start time"))?-1), sub_stop.ok_or(anyhow!(""))?, limit, pool).await? {
+            for potential in store.short_lived_window(window, sub_start, sub_stop, limit, &filter).await? {
                 yield potential;
             }
         }
@@ -188,12 +551,34 @@ pub(crate) struct PotentialHijack {
     pub(crate) asn: i64,
 }
 
+/// Narrows FindShortLived down to some origins or some address space, an empty list lets everything through
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ShortLivedFilter {
+    /// Origin ASNs
+    pub(crate) asns: Vec<i64>,
+    /// Announced prefixes have to be inside or around one of these
+    pub(crate) prefixes: Vec<IpNetwork>,
+}
+
+impl ShortLivedFilter {
+    /// Same as `prefix <<= f OR prefix >>= f` for any of the prefixes in SQL
+    pub(crate) fn matches(&self, asn: i64, prefix: &IpNetwork) -> bool {
+        (self.asns.is_empty() || self.asns.contains(&asn))
+            && (self.prefixes.is_empty()
+                || self.prefixes.iter().any(|f| {
+                    (f.contains(prefix.network()) && prefix.prefix() >= f.prefix())
+                        || (prefix.contains(f.network()) && f.prefix() >= prefix.prefix())
+                }))
+    }
+}
+
 impl PotentialHijack {
     async fn query_short_lived_window(
         window: UnixTimeStamp,
         start: UnixTimeStamp,
         stop: UnixTimeStamp,
         limit: Option<i64>,
+        filter: &ShortLivedFilter,
         pool: &sqlx::PgPool,
     ) -> Result<Vec<Self>> {
         debug!("Running sub-window of short lived query");
@@ -206,19 +591,22 @@ impl PotentialHijack {
 SELECT
       a1.asn                          AS asn,
       a1.prefix                       AS prefix,
-      to_timestamp(MIN(a2.timestamp)) AS "wd_time!",
-      to_timestamp(a1.timestamp)      AS "ann_time!"
-FROM Announcement_new AS a1
-        JOIN Announcement_new AS a2 ON a1.prefix = a2.prefix
+      MIN(a2.timestamp) AS "wd_time!",
+      a1.timestamp      AS "ann_time!"
+FROM Announcement AS a1
+        JOIN Announcement AS a2 ON a1.prefix = a2.prefix
    AND a1.asn = a2.asn
    AND a2.withdrawal = true
-   AND ABS(a1.timestamp - a2.timestamp) < $1
+   AND a2.timestamp - a1.timestamp < make_interval(secs => $1)
    AND a2.timestamp > a1.timestamp
 WHERE a1.withdrawal = FALSE
 AND a2.timestamp < $2
 AND a1.timestamp < $3
 AND a2.timestamp >= $4
 AND a1.timestamp >= $5
+AND (cardinality($7::int8[]) = 0 OR a1.asn = ANY($7))
+AND (cardinality($8::inet[]) = 0
+    OR EXISTS (SELECT FROM unnest($8::inet[]) AS f(net) WHERE a1.prefix <<= f.net OR a1.prefix >>= f.net))
 GROUP BY a1.id,
         a1.asn,
         a1.prefix,
@@ -226,12 +614,14 @@ GROUP BY a1.id,
         a1.timestamp
 LIMIT $6
 "#,
-                f64::from(window),
-                f64::from(stop + window), // beyond the window, no valid withdraws are present
-                f64::from(stop),          // end of ann window
-                f64::from(start),         // start of ann window, withdraws may be immediate
-                f64::from(start),
-                (n)
+                window as f64,
+                to_datetime(stop + window)?, // beyond the window, no valid withdraws are present
+                to_datetime(stop)?,          // end of ann window
+                to_datetime(start)?,         // start of ann window, withdraws may be immediate
+                to_datetime(start)?,
+                (n),
+                &filter.asns,
+                &filter.prefixes
             )
             .fetch_all(pool)
             .await?
@@ -242,30 +632,35 @@ LIMIT $6
 SELECT
       a1.asn                          AS asn,
       a1.prefix                       AS prefix,
-      to_timestamp(MIN(a2.timestamp)) AS "wd_time!",
-      to_timestamp(a1.timestamp)      AS "ann_time!"
-FROM Announcement_new AS a1
-        JOIN Announcement_new AS a2 ON a1.prefix = a2.prefix
+      MIN(a2.timestamp) AS "wd_time!",
+      a1.timestamp      AS "ann_time!"
+FROM Announcement AS a1
+        JOIN Announcement AS a2 ON a1.prefix = a2.prefix
    AND a1.asn = a2.asn
    AND a2.withdrawal = true
-   AND ABS(a1.timestamp - a2.timestamp) < $1
+   AND a2.timestamp - a1.timestamp < make_interval(secs => $1)
    AND a2.timestamp > a1.timestamp
 WHERE a1.withdrawal = FALSE
 AND a2.timestamp < $2
 AND a1.timestamp < $3
 AND a2.timestamp >= $4
 AND a1.timestamp >= $5
+AND (cardinality($6::int8[]) = 0 OR a1.asn = ANY($6))
+AND (cardinality($7::inet[]) = 0
+    OR EXISTS (SELECT FROM unnest($7::inet[]) AS f(net) WHERE a1.prefix <<= f.net OR a1.prefix >>= f.net))
 GROUP BY a1.id,
         a1.asn,
         a1.prefix,
         a1.as_path_segments,
         a1.timestamp
 "#,
-                f64::from(window),
-                f64::from(stop + window), // beyond the window, no valid withdraws are present
-                f64::from(stop),          // end of ann window
-                f64::from(start),         // start of ann window, withdraws may be immediate
-                f64::from(start),
+                window as f64,
+                to_datetime(stop + window)?, // beyond the window, no valid withdraws are present
+                to_datetime(stop)?,          // end of ann window
+                to_datetime(start)?,         // start of ann window, withdraws may be immediate
+                to_datetime(start)?,
+                &filter.asns,
+                &filter.prefixes
             )
             .fetch_all(pool)
             .await?
@@ -275,3 +670,106 @@ GROUP BY a1.id,
         Ok(tmp)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::macros::datetime;
+
+    fn row(
+        id: i64,
+        withdrawal: bool,
+        timestamp: OffsetDateTime,
+        prefix: &str,
+        as_path_segments: Vec<ASPathSeg>,
+    ) -> Announcement {
+        Announcement {
+            id,
+            asn: 64_496,
+            withdrawal,
+            timestamp,
+            prefix: prefix.parse().unwrap(),
+            as_path_segments,
+        }
+    }
+
+    fn seg(seq: bool, confed: bool, as_path: &[i64]) -> ASPathSeg {
+        ASPathSeg {
+            seq,
+            confed,
+            as_path: as_path.to_vec(),
+        }
+    }
+
+    /// Rows encoded by [`Announcement::write_binary`] read back the same from a table like `Announcement`
+    #[sqlx::test]
+    async fn binary_copy_round_trips(pool: sqlx::PgPool) -> Result<()> {
+        let rows = vec![
+            row(
+                i64::MIN,
+                false,
+                datetime!(2022-08-16 22:00:00.123456 UTC),
+                "192.0.2.0/24",
+                vec![seg(true, false, &[64_496, 4_200_000_000, 13_335])],
+            ),
+            row(
+                -1,
+                true,
+                datetime!(1999-12-31 23:59:59.999999 UTC),
+                "10.1.2.3/8",
+                vec![],
+            ),
+            row(
+                0,
+                false,
+                datetime!(2000-01-01 00:00:00 UTC),
+                "0.0.0.0/0",
+                vec![],
+            ),
+            row(
+                1,
+                false,
+                datetime!(2040-02-29 12:00:00.000001 UTC),
+                "2001:db8::/32",
+                vec![
+                    seg(true, true, &[65_000, 65_001]),
+                    seg(true, false, &[64_496]),
+                    seg(false, false, &[64_497, 64_498]),
+                    seg(false, true, &[]),
+                ],
+            ),
+            row(
+                i64::MAX,
+                true,
+                datetime!(1970-01-01 00:00:00 UTC),
+                "2001:db8::1/128",
+                vec![],
+            ),
+        ];
+
+        let oid = sqlx::query_scalar!(r#"SELECT 'as_path_segment'::regtype::oid AS "oid!""#)
+            .fetch_one(&pool)
+            .await?;
+        let mut data = COPY_HEADER.to_vec();
+        for row in rows.iter() {
+            row.write_binary(&mut data, oid.0);
+        }
+        data.extend_from_slice(COPY_TRAILER);
+
+        let mut conn = pool.acquire().await?;
+        sqlx::query("CREATE TEMP TABLE scratch (LIKE announcement)")
+            .execute(&mut *conn)
+            .await?;
+        let mut copy = conn
+            .copy_in_raw("COPY scratch FROM STDIN (FORMAT binary)")
+            .await?;
+        copy.send(data).await?;
+        copy.finish().await?;
+
+        let read: Vec<Announcement> = sqlx::query_as("SELECT * FROM scratch ORDER BY id")
+            .fetch_all(&mut *conn)
+            .await?;
+        assert_eq!(read, rows);
+        Ok(())
+    }
+}
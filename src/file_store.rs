@@ -0,0 +1,463 @@
+// Logs and Errors
+use anyhow::{anyhow, Context, Result};
+use log::{debug, info, warn};
+
+// types
+use crate::bgp::FileReport;
+use crate::db_writer::types::{from_micros, to_micros, ASPathSeg, Announcement, UnixTimeStamp};
+use crate::db_writer::{PotentialHijack, ShortLivedFilter};
+use crate::pipeline::{FileBatch, FileStream};
+use crate::rollup::Rollup;
+use crate::storage::Storage;
+use ipnetwork::IpNetwork;
+use std::net::IpAddr;
+use time::OffsetDateTime;
+
+// Arrow
+use arrow::array::{Array, ArrayRef, BooleanArray, Int64Array, StringArray, TimestampMicrosecondArray};
+use arrow::datatypes::{DataType, Field, Schema, TimeUnit};
+use arrow::ipc::{reader::FileReader, writer::FileWriter};
+use arrow::record_batch::RecordBatch;
+
+// bag of tools
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+const HOUR: i64 = 3600;
+const MANIFEST: &str = "ingested_file.jsonl";
+
+/// Announcements as Arrow IPC files under `root`, partitioned as `collector=<id>/hour=<unix hour>/<batch>.arrow`, a file is written in numbered batches.
+/// The per-file checkpoints `Postgres` keeps in `ingested_file` are appended to `root/ingested_file.jsonl`.
+/// Detector queries scan the partitions they need, good enough for a laptop sized window.
+/// Rollups of every batch are kept as `rollup/collector=<id>/<batch>.json`, outside the hour partitions.
+#[derive(Clone)]
+pub(crate) struct FileStore {
+    root: PathBuf,
+    manifest: Arc<Mutex<()>>, // serialises appends to the manifest
+}
+
+/// One line of the manifest, the last line for a url wins
+#[derive(Serialize, Deserialize)]
+struct ManifestEntry {
+    url: String,
+    collector: String,
+    status: String,
+    row_count: u64,
+    corrupt_records: usize,
+    error: Option<String>,
+}
+
+impl FileStore {
+    pub(crate) fn open(root: PathBuf) -> Result<Self> {
+        std::fs::create_dir_all(&root)
+            .with_context(|| format!("Failed while creating data dir {}", root.display()))?;
+        info!("Using file store at {}", root.display());
+        Ok(FileStore {
+            root,
+            manifest: Arc::new(Mutex::new(())),
+        })
+    }
+
+    fn append_manifest(&self, entry: &ManifestEntry) -> Result<()> {
+        let _guard = self.manifest.lock().map_err(|_| anyhow!("Manifest lock poisoned"))?;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.root.join(MANIFEST))?;
+        writeln!(file, "{}", serde_json::to_string(entry)?)?;
+        Ok(())
+    }
+
+    /// Every hour partition of any collector with the start of its hour
+    fn hours(&self) -> Result<Vec<(i64, PathBuf)>> {
+        let mut hours = vec![];
+        for collector in std::fs::read_dir(&self.root)? {
+            let collector = collector?.path();
+            if !collector.is_dir() {
+                continue;
+            }
+            for hour in std::fs::read_dir(&collector)? {
+                let hour = hour?.path();
+                let Some(hour_start) = hour
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .and_then(|n| n.strip_prefix("hour="))
+                    .and_then(|n| n.parse::<i64>().ok())
+                else {
+                    continue;
+                };
+                hours.push((hour_start, hour));
+            }
+        }
+        Ok(hours)
+    }
+
+    /// Every `.arrow` file in an hour partition overlapping `[start, stop)`, of any collector
+    fn partitions(&self, start: i64, stop: i64) -> Result<Vec<PathBuf>> {
+        let mut files = vec![];
+        for (hour_start, hour) in self.hours()? {
+            if hour_start + HOUR <= start || hour_start >= stop {
+                continue;
+            }
+            for file in std::fs::read_dir(&hour)? {
+                let file = file?.path();
+                if file.extension().is_some_and(|e| e == "arrow") {
+                    files.push(file);
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Reads the rows of every partition overlapping `[start, stop)` that match `keep`
+    fn scan(
+        &self,
+        start: i64,
+        stop: i64,
+        keep: impl Fn(&Announcement) -> bool,
+    ) -> Result<Vec<Announcement>> {
+        let mut rows = vec![];
+        for path in self.partitions(start, stop)? {
+            debug!("Scanning {}", path.display());
+            let reader = FileReader::try_new(BufReader::new(File::open(&path)?), None)
+                .with_context(|| format!("Failed while opening {}", path.display()))?;
+            for batch in reader {
+                rows.extend(from_record_batch(&batch?)?.into_iter().filter(|a| keep(a)));
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Writes `rows` as `<batch>.arrow.tmp` into their hour partitions and their rollup as `<batch>.json.tmp`,
+    /// returning every temporary file with the name it is published under
+    fn write_rows(
+        &self,
+        collector: &str,
+        batch: &str,
+        rows: &[Announcement],
+    ) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let mut written = vec![];
+        let mut hours: BTreeMap<i64, Vec<&Announcement>> = BTreeMap::new();
+        for row in rows.iter() {
+            hours
+                .entry(row.timestamp.unix_timestamp().div_euclid(HOUR) * HOUR)
+                .or_default()
+                .push(row);
+        }
+        for (hour, rows) in hours {
+            let dir = self
+                .root
+                .join(format!("collector={collector}"))
+                .join(format!("hour={hour}"));
+            std::fs::create_dir_all(&dir)?;
+            let tmp = dir.join(format!("{batch}.arrow.tmp"));
+            written.push((tmp.clone(), dir.join(format!("{batch}.arrow"))));
+            let mut writer = FileWriter::try_new(BufWriter::new(File::create(&tmp)?), &schema())?;
+            writer.write(&to_record_batch(&rows)?)?;
+            writer.finish()?;
+        }
+        // rollups are summed by whoever reads them, one file per batch keeps re-runs idempotent
+        let dir = self.root.join("rollup").join(format!("collector={collector}"));
+        std::fs::create_dir_all(&dir)?;
+        let tmp = dir.join(format!("{batch}.json.tmp"));
+        written.push((tmp.clone(), dir.join(format!("{batch}.json"))));
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        serde_json::to_writer(&mut writer, &Rollup::of(rows))?;
+        writer.flush()?;
+        Ok(written)
+    }
+
+    /// Removes the batches an earlier run wrote for `name` from the directories of `written`, then renames
+    /// the temporary files into place
+    fn publish(&self, name: &str, written: &mut Vec<(PathBuf, PathBuf)>) -> Result<()> {
+        let dirs: HashSet<_> = written.iter().filter_map(|(_, path)| path.parent()).collect();
+        for dir in dirs {
+            for file in std::fs::read_dir(dir)? {
+                let file = file?.path();
+                if file
+                    .file_name()
+                    .and_then(|n| n.to_str())
+                    .is_some_and(|n| is_batch_of(n, name))
+                {
+                    std::fs::remove_file(file)?;
+                }
+            }
+        }
+        for (tmp, path) in written.drain(..) {
+            std::fs::rename(tmp, path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for FileStore {
+    async fn ingested_files(&self) -> Result<HashSet<String>> {
+        let path = self.root.join(MANIFEST);
+        if !path.exists() {
+            return Ok(HashSet::new());
+        }
+        let mut latest = HashMap::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let entry: ManifestEntry = serde_json::from_str(&line?)?;
+            latest.insert(entry.url, entry.status);
+        }
+        Ok(latest
+            .into_iter()
+            .filter(|(_, status)| status == "loaded" || status == "partial")
+            .map(|(url, _)| url)
+            .collect())
+    }
+
+    /// Writes the rows of every hour of every batch to its own file, named after the MRT file so a re-run replaces
+    /// rather than duplicates it. The files are written under a temporary name and only renamed once the whole
+    /// MRT file is parsed, replacing those of an earlier run, the manifest line is added last.
+    async fn write_file(
+        &self,
+        collector: &str,
+        mut batches: FileStream,
+    ) -> Result<(FileReport, u64)> {
+        let store = self.clone();
+        let collector = collector.to_string();
+        tokio::task::spawn_blocking(move || -> Result<(FileReport, u64)> {
+            let url = batches.url();
+            let name = url.rsplit('/').next().unwrap_or(url).to_string();
+            let mut written = vec![];
+            let (mut batch, mut copied) = (0, 0u64);
+            let result = loop {
+                match batches.next() {
+                    Some(FileBatch::Rows(rows)) => {
+                        match store.write_rows(&collector, &format!("{name}.{batch}"), &rows) {
+                            Ok(files) => written.extend(files),
+                            Err(e) => break Err(e),
+                        }
+                        batch += 1;
+                        copied += rows.len() as u64;
+                    }
+                    Some(FileBatch::Restart) => {
+                        discard(&mut written);
+                        (batch, copied) = (0, 0);
+                    }
+                    Some(FileBatch::Done(report)) if report.error.is_some() => break Ok((report, 0)),
+                    Some(FileBatch::Done(report)) => {
+                        break store
+                            .publish(&name, &mut written)
+                            .and_then(|_| {
+                                store.append_manifest(&ManifestEntry {
+                                    url: report.url.clone(),
+                                    collector: collector.clone(),
+                                    status: report.status().to_string(),
+                                    row_count: copied,
+                                    corrupt_records: report.corrupt_records,
+                                    error: None,
+                                })
+                            })
+                            .map(|_| (report, copied))
+                    }
+                    None => break Err(anyhow!("The parser went away before the end of the file")),
+                }
+            };
+            discard(&mut written);
+            result
+        })
+        .await?
+    }
+
+    async fn write_batch(&self, collector: &str, rows: Vec<Announcement>) -> Result<u64> {
+        let store = self.clone();
+        let collector = collector.to_string();
+        tokio::task::spawn_blocking(move || -> Result<u64> {
+            let name = format!("live-{}", uuid::Uuid::new_v4());
+            let mut written = store.write_rows(&collector, &name, &rows)?;
+            store.publish(&name, &mut written)?;
+            Ok(rows.len() as u64)
+        })
+        .await?
+    }
+
+    async fn record_failure(&self, report: &FileReport) -> Result<()> {
+        let store = self.clone();
+        let entry = ManifestEntry {
+            url: report.url.clone(),
+            collector: report.collector.clone(),
+            status: report.status().to_string(),
+            row_count: 0,
+            corrupt_records: report.corrupt_records,
+            error: report.error.clone(),
+        };
+        tokio::task::spawn_blocking(move || store.append_manifest(&entry)).await?
+    }
+
+    /// Nothing to index, the partitions are the index
+    async fn finish_ingest(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Removes the hour partitions that ended before `before`, the manifest keeps their files as ingested
+    async fn prune(&self, before: UnixTimeStamp) -> Result<()> {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            for (hour_start, hour) in store.hours()? {
+                if hour_start + HOUR <= before {
+                    std::fs::remove_dir_all(&hour)
+                        .with_context(|| format!("Failed while removing {}", hour.display()))?;
+                    info!("Pruned {}", hour.display());
+                }
+            }
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Same semantics as the SQL of [`PotentialHijack::query_short_lived_window`], done in memory
+    async fn short_lived_window(
+        &self,
+        window: UnixTimeStamp,
+        start: UnixTimeStamp,
+        stop: UnixTimeStamp,
+        limit: Option<i64>,
+        filter: &ShortLivedFilter,
+    ) -> Result<Vec<PotentialHijack>> {
+        let store = self.clone();
+        let filter = filter.clone();
+        tokio::task::spawn_blocking(move || -> Result<Vec<PotentialHijack>> {
+            let rows = store.scan(start, stop + window, |a| {
+                let timestamp = a.timestamp.unix_timestamp();
+                timestamp >= start
+                    && if a.withdrawal {
+                        timestamp < stop + window
+                    } else {
+                        timestamp < stop
+                    }
+            })?;
+
+            let mut withdrawals: HashMap<(i64, IpNetwork), Vec<OffsetDateTime>> = HashMap::new();
+            for wd in rows.iter().filter(|a| a.withdrawal) {
+                withdrawals.entry((wd.asn, wd.prefix)).or_default().push(wd.timestamp);
+            }
+            withdrawals.values_mut().for_each(|v| v.sort_unstable());
+
+            Ok(rows
+                .iter()
+                .filter(|a| !a.withdrawal && filter.matches(a.asn, &a.prefix))
+                .filter_map(|ann| {
+                    let wds = withdrawals.get(&(ann.asn, ann.prefix))?;
+                    // first withdrawal strictly after the announcement
+                    let wd = *wds.get(wds.partition_point(|&t| t <= ann.timestamp))?;
+                    if wd - ann.timestamp >= time::Duration::seconds(window) {
+                        return None;
+                    }
+                    Some(PotentialHijack {
+                        prefix: ann.prefix,
+                        ann_time: ann.timestamp,
+                        wd_time: wd,
+                        asn: ann.asn,
+                    })
+                })
+                .take(limit.map_or(usize::MAX, |n| n as usize))
+                .collect())
+        })
+        .await?
+    }
+
+    /// Matches `inet >>`, the prefix has to strictly contain `ip`
+    async fn ip_search(&self, ip: IpAddr) -> Result<Vec<Announcement>> {
+        let store = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let host = IpNetwork::from(ip).prefix();
+            store.scan(i64::MIN / 2, i64::MAX / 2, |a| {
+                !a.withdrawal && a.prefix.prefix() < host && a.prefix.contains(ip)
+            })
+        })
+        .await?
+    }
+}
+
+/// Whether `file` is a published batch of `name`, `<name>.<n>.arrow` or its rollup, or the single file
+/// earlier runs wrote
+fn is_batch_of(file: &str, name: &str) -> bool {
+    let Some(rest) = file.strip_prefix(name) else {
+        return false;
+    };
+    let Some(rest) = rest
+        .strip_suffix(".arrow")
+        .or_else(|| rest.strip_suffix(".json"))
+    else {
+        return false;
+    };
+    rest.is_empty()
+        || rest
+            .strip_prefix('.')
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Removes the temporary files of a file that is not published
+fn discard(written: &mut Vec<(PathBuf, PathBuf)>) {
+    for (tmp, _) in written.drain(..) {
+        if let Err(e) = std::fs::remove_file(&tmp) {
+            warn!("Could not remove {}, {e}", tmp.display());
+        }
+    }
+}
+
+fn schema() -> Schema {
+    Schema::new(vec![
+        Field::new("id", DataType::Int64, false),
+        Field::new("asn", DataType::Int64, false),
+        Field::new("withdrawal", DataType::Boolean, false),
+        Field::new("timestamp", DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())), false),
+        Field::new("prefix", DataType::Utf8, false),
+        Field::new("as_path_segments", DataType::Utf8, false), // JSON, see [`ASPathSeg`]
+    ])
+}
+
+fn to_record_batch(rows: &[&Announcement]) -> Result<RecordBatch> {
+    let segments = rows
+        .iter()
+        .map(|a| serde_json::to_string(&a.as_path_segments))
+        .collect::<Result<Vec<String>, _>>()?;
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(Int64Array::from_iter_values(rows.iter().map(|a| a.id))),
+        Arc::new(Int64Array::from_iter_values(rows.iter().map(|a| a.asn))),
+        Arc::new(BooleanArray::from(rows.iter().map(|a| a.withdrawal).collect::<Vec<bool>>())),
+        Arc::new(
+            TimestampMicrosecondArray::from_iter_values(rows.iter().map(|a| to_micros(a.timestamp)))
+                .with_timezone("UTC"),
+        ),
+        Arc::new(StringArray::from_iter_values(rows.iter().map(|a| a.prefix.to_string()))),
+        Arc::new(StringArray::from_iter_values(segments)),
+    ];
+    Ok(RecordBatch::try_new(Arc::new(schema()), columns)?)
+}
+
+fn from_record_batch(batch: &RecordBatch) -> Result<Vec<Announcement>> {
+    fn column<'a, T: Array + 'static>(batch: &'a RecordBatch, name: &str) -> Result<&'a T> {
+        batch
+            .column_by_name(name)
+            .and_then(|c| c.as_any().downcast_ref::<T>())
+            .ok_or(anyhow!("Column {name} is missing or has the wrong type"))
+    }
+    let id = column::<Int64Array>(batch, "id")?;
+    let asn = column::<Int64Array>(batch, "asn")?;
+    let withdrawal = column::<BooleanArray>(batch, "withdrawal")?;
+    let timestamp = column::<TimestampMicrosecondArray>(batch, "timestamp")?;
+    let prefix = column::<StringArray>(batch, "prefix")?;
+    let segments = column::<StringArray>(batch, "as_path_segments")?;
+
+    (0..batch.num_rows())
+        .map(|i| {
+            Ok(Announcement {
+                id: id.value(i),
+                asn: asn.value(i),
+                withdrawal: withdrawal.value(i),
+                timestamp: from_micros(timestamp.value(i))?,
+                prefix: IpNetwork::from_str(prefix.value(i))?,
+                as_path_segments: serde_json::from_str::<Vec<ASPathSeg>>(segments.value(i))?,
+            })
+        })
+        .collect()
+}
@@ -0,0 +1,248 @@
+// Logs and Errors
+use anyhow::{anyhow, Result};
+use log::warn;
+
+// types
+use crate::blocklist::Blocklists;
+use crate::config::IntelSettings;
+use crate::prefix_trie::PrefixMatch;
+use crate::response_cache::{CacheMode, ResponseCache};
+use crate::seclytics_api::{ApiOptions, Seclytics};
+use ipnetwork::IpNetwork;
+use std::future::Future;
+
+// bag of tools
+use futures::future::join_all;
+use itertools::Itertools;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Whether a provider knows an ASN or prefix as bad, how sure it is and why
+#[derive(Debug, Clone)]
+pub(crate) struct Verdict {
+    pub(crate) malicious: bool,
+    /// 0 for a guess to 1 for certain
+    pub(crate) confidence: f64,
+    /// Providers that reported it, comma separated once merged
+    pub(crate) source: String,
+    pub(crate) reasons: Vec<String>,
+    /// The listed prefixes an announced prefix matched, empty for ASNs
+    pub(crate) matches: Vec<PrefixMatch>,
+}
+
+impl Verdict {
+    /// Bad when any of them says so, confidence combines as independent evidence
+    fn merge(self, other: Verdict) -> Verdict {
+        let confidence = match (self.malicious, other.malicious) {
+            (true, true) => 1.0 - (1.0 - self.confidence) * (1.0 - other.confidence),
+            (true, false) => self.confidence,
+            (false, true) => other.confidence,
+            (false, false) => self.confidence.max(other.confidence),
+        };
+        Verdict {
+            malicious: self.malicious || other.malicious,
+            confidence,
+            source: format!("{}, {}", self.source, other.source),
+            reasons: self.reasons.into_iter().chain(other.reasons).collect(),
+            matches: self.matches.into_iter().chain(other.matches).collect(),
+        }
+    }
+}
+
+/// What the providers know about an ASN and the prefixes it announced
+#[derive(Debug, Default)]
+pub(crate) struct Findings {
+    /// `None` when no provider knows the ASN
+    pub(crate) asn: Option<Verdict>,
+    /// Only the prefixes some provider knows as bad
+    pub(crate) prefixes: BTreeMap<IpNetwork, Verdict>,
+    /// What a provider could not make sense of without failing the lookup, e.g. a malformed listing
+    pub(crate) errors: Vec<anyhow::Error>,
+}
+
+impl Findings {
+    pub(crate) fn asn_is_malicious(&self) -> bool {
+        self.asn.as_ref().is_some_and(|v| v.malicious)
+    }
+
+    fn merge(mut self, other: Findings) -> Findings {
+        self.asn = match (self.asn, other.asn) {
+            (Some(a), Some(b)) => Some(a.merge(b)),
+            (a, b) => a.or(b),
+        };
+        for (prefix, verdict) in other.prefixes {
+            let merged = match self.prefixes.remove(&prefix) {
+                Some(known) => known.merge(verdict),
+                None => verdict,
+            };
+            self.prefixes.insert(prefix, merged);
+        }
+        self.errors.extend(other.errors);
+        self
+    }
+}
+
+/// An ASN and the prefixes it announced, what is looked up
+#[derive(Debug, Clone)]
+pub(crate) struct Query {
+    pub(crate) asn: i64,
+    pub(crate) prefixes: Vec<IpNetwork>,
+}
+
+/// A source of threat intelligence on ASNs and prefixes
+pub(crate) trait ThreatIntel: Send + Sync {
+    /// What is known about `asn` and which of `prefixes`, announced by it, are known bad
+    fn lookup(
+        &self,
+        asn: i64,
+        prefixes: &[IpNetwork],
+    ) -> impl Future<Output = Result<Findings>> + Send;
+
+    /// Several lookups at once, answered in the order asked. Providers with a bulk API override this,
+    /// the others answer one by one.
+    fn lookup_many(&self, queries: &[Query]) -> impl Future<Output = Vec<Result<Findings>>> + Send {
+        async move { join_all(queries.iter().map(|q| self.lookup(q.asn, &q.prefixes))).await }
+    }
+}
+
+/// Every provider there is, see [`Intel`]
+pub(crate) enum Provider {
+    Seclytics(Box<Seclytics>),
+    Blocklist(Blocklists),
+}
+
+/// Providers that can be picked in the config or on the command line
+#[derive(clap::ValueEnum, serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Source {
+    /// The Seclytics API, needs network access
+    Seclytics,
+    /// Local blocklist files, works offline
+    Blocklist,
+}
+
+impl ThreatIntel for Provider {
+    async fn lookup(&self, asn: i64, prefixes: &[IpNetwork]) -> Result<Findings> {
+        match self {
+            Provider::Seclytics(seclytics) => seclytics.lookup(asn, prefixes).await,
+            Provider::Blocklist(blocklists) => blocklists.lookup(asn, prefixes).await,
+        }
+    }
+
+    async fn lookup_many(&self, queries: &[Query]) -> Vec<Result<Findings>> {
+        match self {
+            Provider::Seclytics(seclytics) => seclytics.lookup_many(queries).await,
+            Provider::Blocklist(blocklists) => blocklists.lookup_many(queries).await,
+        }
+    }
+}
+
+impl Provider {
+    fn name(&self) -> &'static str {
+        match self {
+            Provider::Seclytics(_) => "seclytics",
+            Provider::Blocklist(_) => "blocklist",
+        }
+    }
+}
+
+/// Asks every provider and merges their verdicts. A provider that fails is logged and left out,
+/// the lookup only fails when all of them do.
+pub(crate) struct Intel {
+    providers: Vec<Provider>,
+}
+
+impl Intel {
+    pub(crate) fn new(providers: Vec<Provider>) -> Self {
+        Intel { providers }
+    }
+
+    /// The providers `settings` ask for
+    pub(crate) fn open(settings: &IntelSettings) -> Result<Self> {
+        let mut providers = vec![];
+        for source in settings.providers.iter().unique() {
+            providers.push(match source {
+                Source::Seclytics => Provider::Seclytics(Box::new(Seclytics::new(
+                    reqwest::Client::builder()
+                        .timeout(Duration::from_secs(30))
+                        .build()?,
+                    response_cache(settings),
+                    ApiOptions {
+                        endpoint: settings.seclytics.endpoint.clone(),
+                        token: settings.seclytics.token.clone(),
+                        requests_per_second: settings.seclytics.rps,
+                        retries: settings.seclytics.retries,
+                        batch_size: settings.seclytics.batch_size,
+                    },
+                    &settings.prefix_match,
+                ))),
+                Source::Blocklist => Provider::Blocklist(Blocklists::open(
+                    settings.blocklists.clone(),
+                    Duration::from_secs(settings.blocklist_refresh_mins.max(1) * 60),
+                    &settings.prefix_match,
+                )?),
+            });
+        }
+        Ok(Intel::new(providers))
+    }
+}
+
+fn response_cache(settings: &IntelSettings) -> ResponseCache {
+    let mode = if settings.offline {
+        CacheMode::Offline
+    } else if settings.refresh {
+        CacheMode::Refresh
+    } else {
+        CacheMode::Normal
+    };
+    ResponseCache::new(
+        settings.cache_dir.clone(),
+        Duration::from_secs(settings.cache_ttl_hours * 3600),
+        mode,
+    )
+}
+
+impl ThreatIntel for Intel {
+    async fn lookup(&self, asn: i64, prefixes: &[IpNetwork]) -> Result<Findings> {
+        let query = Query {
+            asn,
+            prefixes: prefixes.to_vec(),
+        };
+        self.lookup_many(&[query])
+            .await
+            .pop()
+            .unwrap_or_else(|| Ok(Findings::default()))
+    }
+
+    async fn lookup_many(&self, queries: &[Query]) -> Vec<Result<Findings>> {
+        let answers = join_all(self.providers.iter().map(|p| p.lookup_many(queries))).await;
+        let mut answers: Vec<_> = answers.into_iter().map(Vec::into_iter).collect();
+        let mut merged = Vec::with_capacity(queries.len());
+        for query in queries {
+            let mut findings = Findings::default();
+            let mut last_error = None;
+            let mut answered = false;
+            for (provider, answers) in self.providers.iter().zip(answers.iter_mut()) {
+                match answers.next() {
+                    Some(Ok(found)) => {
+                        findings = findings.merge(found);
+                        answered = true;
+                    }
+                    Some(Err(e)) => {
+                        warn!("{} could not look up AS{}, {e:#}", provider.name(), query.asn);
+                        last_error = Some(e);
+                    }
+                    None => {
+                        warn!("{} left AS{} unanswered", provider.name(), query.asn);
+                        last_error = Some(anyhow!("{} left AS{} unanswered", provider.name(), query.asn));
+                    }
+                }
+            }
+            merged.push(match last_error {
+                Some(e) if !answered => Err(e),
+                _ => Ok(findings),
+            });
+        }
+        merged
+    }
+}
@@ -0,0 +1,493 @@
+// Logs and Errors
+use anyhow::{anyhow, Context, Result};
+use log::{debug, error, info, warn};
+
+// BGP data
+use crate::bgp::elem_to_announcement;
+use crate::db_writer::types::Announcement;
+use crate::pipeline::{ToWriter, WriterSender};
+use bgpkit_parser::{
+    models::Asn,
+    parse_bmp_msg, parse_ris_live_message,
+    parser::bmp::messages::{BmpMessage, MessageBody},
+    BgpElem, Elementor,
+};
+
+// Networking
+use bytes::{Bytes, BytesMut};
+use futures::{SinkExt, StreamExt};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::sync::CancellationToken;
+
+// Data Processing
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const FLUSH_ROWS: usize = 10_000;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+const BMP_HEADER_LEN: usize = 6; // version (1) + message length (4) + message type (1), RFC 7854 section 4.1
+const BMP_VERSION: u8 = 3;
+
+/// Collects rows from a live feed, per collector, and hands them to the writer every [`FLUSH_ROWS`] rows or
+/// [`FLUSH_INTERVAL`], whichever comes first. Every flush is copied in its own transaction so the rows are
+/// committed straight away.
+struct LiveBuffer {
+    rows: HashMap<String, Vec<Announcement>>,
+    count: usize,
+    last_flush: Instant,
+    sender: WriterSender,
+}
+
+impl LiveBuffer {
+    fn new(sender: WriterSender) -> Self {
+        LiveBuffer {
+            rows: HashMap::new(),
+            count: 0,
+            last_flush: Instant::now(),
+            sender,
+        }
+    }
+
+    fn push(&mut self, collector: &str, elems: Vec<BgpElem>) -> Result<()> {
+        let rows = self.rows.entry(collector.to_string()).or_default();
+        self.count += elems.len();
+        rows.extend(elems.into_iter().map(|elem| elem_to_announcement(elem, collector)));
+        if self.count >= FLUSH_ROWS || self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.last_flush = Instant::now();
+        if self.count == 0 {
+            return Ok(());
+        }
+        debug!("Flushing {} live rows", self.count);
+        self.count = 0;
+        for (collector, rows) in self.rows.drain() {
+            let msg = ToWriter::Rows { collector, rows };
+            // the writer queue blocks while the write buffer is full, keep that off the async workers
+            tokio::task::block_in_place(|| self.sender.send(msg))
+                .context("Channel Disconnected, writer is gone")?;
+        }
+        Ok(())
+    }
+}
+
+/// Subscribes to a RIS Live websocket (`wss://ris-live.ripe.net/v1/ws/`) and streams every update into `sender`
+/// until the server closes the connection or `stop` is cancelled. Rows keep the collector that saw them, the
+/// message's `host`.
+pub(crate) async fn ris_live(
+    url: &str,
+    collector: Option<&str>,
+    sender: WriterSender,
+    stop: CancellationToken,
+) -> Result<()> {
+    let (ws, _) = tokio_tungstenite::connect_async(url)
+        .await
+        .with_context(|| format!("Failed while connecting to RIS Live at {url}"))?;
+    info!("Connected to RIS Live at {url}");
+    let (mut write, mut read) = ws.split();
+
+    let mut data = serde_json::json!({ "type": "UPDATE" });
+    if let Some(collector) = collector {
+        data["host"] = serde_json::json!(collector);
+    }
+    write
+        .send(Message::Text(
+            serde_json::json!({ "type": "ris_subscribe", "data": data }).to_string(),
+        ))
+        .await?;
+
+    let mut buffer = LiveBuffer::new(sender);
+    let mut tick = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            msg = read.next() => match msg {
+                None | Some(Ok(Message::Close(_))) => break,
+                Some(Ok(Message::Text(text))) => match decode_ris_message(&text) {
+                    Ok(Some((host, elems))) => buffer.push(&host, elems)?,
+                    Ok(None) => {}
+                    Err(e) => debug!("Skipping RIS Live message, {e:#}"),
+                },
+                Some(Ok(_)) => {}
+                Some(Err(e)) => {
+                    buffer.flush()?;
+                    return Err(e).context("RIS Live connection dropped");
+                }
+            },
+            _ = tick.tick() => buffer.flush()?,
+            _ = stop.cancelled() => {
+                buffer.flush()?;
+                info!("Unsubscribed from RIS Live at {url}");
+                return Ok(());
+            }
+        }
+    }
+    buffer.flush()?;
+    info!("RIS Live at {url} closed the stream");
+    Ok(())
+}
+
+/// Accepts BMP (RFC 7854) sessions on `addr` and streams the route monitoring messages of every session into `sender`.
+/// Runs until the listener fails or `stop` is cancelled, a broken session only ends that session. Either way it
+/// returns once every session has flushed its rows and let go of its sender.
+pub(crate) async fn bmp_listen(addr: &str, sender: WriterSender, stop: CancellationToken) -> Result<()> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("Failed while binding BMP listener to {addr}"))?;
+    info!("Listening for BMP sessions on {addr}");
+    let mut sessions = JoinSet::new();
+    let result = loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => break Err(e).context("BMP listener failed"),
+                };
+                info!("BMP session opened by {peer}");
+                let (sender, stop) = (sender.clone(), stop.clone());
+                sessions.spawn(async move {
+                    match bmp_session(stream, format!("bmp-{}", peer.ip()), sender, stop).await {
+                        Ok(n) => info!("BMP session with {peer} closed after {n} messages"),
+                        Err(e) => error!("BMP session with {peer} failed, {e:#}"),
+                    }
+                });
+            }
+            Some(res) = sessions.join_next() => {
+                if let Err(e) = res {
+                    error!("BMP session panicked, {e}");
+                }
+            }
+            _ = stop.cancelled() => break Ok(()),
+        }
+    };
+    // a failed listener stops the sessions too, they still flush what they have
+    stop.cancel();
+    while let Some(res) = sessions.join_next().await {
+        if let Err(e) = res {
+            error!("BMP session panicked, {e}");
+        }
+    }
+    result
+}
+
+async fn bmp_session(
+    mut stream: TcpStream,
+    collector: String,
+    sender: WriterSender,
+    stop: CancellationToken,
+) -> Result<usize> {
+    let mut buffer = LiveBuffer::new(sender);
+    let mut count = 0usize;
+    let session = async {
+        let mut pending = BytesMut::with_capacity(64 * 1024);
+        let mut tick = tokio::time::interval(FLUSH_INTERVAL);
+        loop {
+            tokio::select! {
+                // `read_buf` is cancel safe, a tick never drops half a message
+                n = stream.read_buf(&mut pending) => {
+                    if n? == 0 {
+                        break;
+                    }
+                    while let Some(mut msg) = next_bmp_frame(&mut pending)? {
+                        count += 1;
+                        match parse_bmp_msg(&mut msg) {
+                            Ok(msg) => buffer.push(&collector, bmp_to_elems(msg))?,
+                            Err(e) => warn!("Skipping BMP message, {e}"),
+                        }
+                    }
+                },
+                _ = tick.tick() => buffer.flush()?,
+                _ = stop.cancelled() => break,
+            }
+        }
+        if !pending.is_empty() {
+            warn!("BMP session ended with {} bytes of a partial message", pending.len());
+        }
+        Ok::<_, anyhow::Error>(())
+    }
+    .await;
+    // the rows of a broken session are kept up to where it broke
+    buffer.flush()?;
+    session.map(|()| count)
+}
+
+/// What is read of a RIS Live message before bgpkit gets it, bgpkit drops the collector
+#[derive(Deserialize)]
+struct RisEnvelope {
+    #[serde(rename = "type")]
+    kind: String,
+    data: Option<RisData>,
+}
+
+#[derive(Deserialize)]
+struct RisData {
+    /// The collector that saw the message, e.g. `rrc21`
+    host: Option<String>,
+}
+
+/// The collector and updates of a RIS Live message, [`None`] for anything but a `ris_message` with updates
+fn decode_ris_message(text: &str) -> Result<Option<(String, Vec<BgpElem>)>> {
+    let envelope: RisEnvelope = serde_json::from_str(text).context("Not a RIS Live message")?;
+    if envelope.kind != "ris_message" {
+        return Ok(None);
+    }
+    let Some(host) = envelope.data.and_then(|data| data.host) else {
+        debug!("Skipping RIS Live message without a host");
+        return Ok(None);
+    };
+    let elems = parse_ris_live_message(text).map_err(|e| anyhow!("{e}"))?;
+    Ok((!elems.is_empty()).then_some((host, elems)))
+}
+
+/// Splits the next complete BMP message off the front of `pending`, [`None`] until enough bytes have arrived.
+fn next_bmp_frame(pending: &mut BytesMut) -> Result<Option<Bytes>> {
+    if pending.len() < BMP_HEADER_LEN {
+        return Ok(None);
+    }
+    if pending[0] != BMP_VERSION {
+        return Err(anyhow!("Corrupt BMP stream, version {} is not {BMP_VERSION}", pending[0]));
+    }
+    let len = u32::from_be_bytes([pending[1], pending[2], pending[3], pending[4]]) as usize;
+    if len < BMP_HEADER_LEN {
+        return Err(anyhow!("Corrupt BMP stream, message length {len} is shorter than the header"));
+    }
+    if pending.len() < len {
+        return Ok(None);
+    }
+    Ok(Some(pending.split_to(len).freeze()))
+}
+
+/// Only route monitoring messages carry updates, peer up/down, stats etc. are dropped.
+fn bmp_to_elems(msg: BmpMessage) -> Vec<BgpElem> {
+    match (msg.per_peer_header, msg.message_body) {
+        (Some(peer), MessageBody::RouteMonitoring(monitoring)) => Elementor::bgp_to_elems(
+            monitoring.bgp_message,
+            peer.timestamp,
+            &peer.peer_ip,
+            &Asn::from(peer.peer_asn),
+        ),
+        _ => vec![],
+    }
+}
+
+/// Serves `recording` (one RIS Live JSON message per line) as a RIS Live websocket on `bind`.
+/// Every client gets the whole recording from the start, meant for testing [`ris_live`] without RIPE.
+pub(crate) async fn replay_ris_live(recording: &Path, bind: &str, delay: Duration) -> Result<()> {
+    let listener = TcpListener::bind(bind)
+        .await
+        .with_context(|| format!("Failed while binding replay server to {bind}"))?;
+    info!("Replaying {} as RIS Live on ws://{bind}", recording.display());
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let recording = recording.to_path_buf();
+        tokio::spawn(async move {
+            match replay_ris_session(stream, &recording, delay).await {
+                Ok(n) => info!("Replayed {n} messages to {peer}"),
+                Err(e) => error!("Replay to {peer} failed, {e:#}"),
+            }
+        });
+    }
+}
+
+async fn replay_ris_session(stream: TcpStream, recording: &Path, delay: Duration) -> Result<usize> {
+    let mut ws = tokio_tungstenite::accept_async(stream).await?;
+    // wait for the subscription like the real service, its filters are ignored
+    ws.next().await;
+
+    let mut lines = BufReader::new(tokio::fs::File::open(recording).await?).lines();
+    let mut count = 0usize;
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        ws.send(Message::Text(line)).await?;
+        count += 1;
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+    ws.close(None).await?;
+    Ok(count)
+}
+
+/// Connects to the BMP listener at `target` and plays `recording`, a raw dump of back to back BMP messages.
+pub(crate) async fn replay_bmp(recording: &Path, target: &str, delay: Duration) -> Result<()> {
+    let mut pending = BytesMut::from(&tokio::fs::read(recording).await?[..]);
+    let mut stream = TcpStream::connect(target)
+        .await
+        .with_context(|| format!("Failed while connecting to BMP listener at {target}"))?;
+    info!("Replaying {} as BMP to {target}", recording.display());
+
+    let mut count = 0usize;
+    while let Some(msg) = next_bmp_frame(&mut pending)? {
+        stream.write_all(&msg).await?;
+        count += 1;
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+    }
+    if !pending.is_empty() {
+        warn!("Ignoring {} trailing bytes of the recording", pending.len());
+    }
+    stream.shutdown().await?;
+    info!("Replayed {count} BMP messages to {target}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::{spawn_writers, writer_queue};
+    use crate::sqlite_store::SqliteStore;
+    use crate::storage::Storage;
+
+    /// A BMP message of `body_len` bytes after the common header, its type is initiation
+    fn bmp_frame(body_len: usize) -> Vec<u8> {
+        let len = (BMP_HEADER_LEN + body_len) as u32;
+        let mut frame = vec![BMP_VERSION];
+        frame.extend_from_slice(&len.to_be_bytes());
+        frame.push(4);
+        frame.resize(len as usize, 0xAB);
+        frame
+    }
+
+    fn ris_update(host: Option<&str>, prefix: &str) -> String {
+        let mut data = serde_json::json!({
+            "timestamp": 1697000000.0,
+            "peer": "192.0.2.1",
+            "peer_asn": "64496",
+            "id": "192.0.2.1-1",
+            "type": "UPDATE",
+            "path": [64496, 13335],
+            "origin": "igp",
+            "announcements": [{ "next_hop": "192.0.2.1", "prefixes": [prefix] }]
+        });
+        if let Some(host) = host {
+            data["host"] = serde_json::json!(host);
+        }
+        serde_json::json!({ "type": "ris_message", "data": data }).to_string()
+    }
+
+    #[test]
+    fn bmp_frames_wait_for_the_whole_message() {
+        let frame = bmp_frame(10);
+        let mut pending = BytesMut::from(&frame[..4]);
+        assert!(
+            next_bmp_frame(&mut pending).unwrap().is_none(),
+            "partial header"
+        );
+        pending.extend_from_slice(&frame[4..12]);
+        assert!(
+            next_bmp_frame(&mut pending).unwrap().is_none(),
+            "partial body"
+        );
+        pending.extend_from_slice(&frame[12..]);
+        assert_eq!(next_bmp_frame(&mut pending).unwrap().unwrap(), frame);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn bmp_frames_split_a_read_of_several() {
+        let (first, second) = (bmp_frame(3), bmp_frame(0));
+        let mut pending = BytesMut::new();
+        pending.extend_from_slice(&first);
+        pending.extend_from_slice(&second);
+        pending.extend_from_slice(&bmp_frame(5)[..7]);
+
+        assert_eq!(next_bmp_frame(&mut pending).unwrap().unwrap(), first);
+        assert_eq!(next_bmp_frame(&mut pending).unwrap().unwrap(), second);
+        assert!(next_bmp_frame(&mut pending).unwrap().is_none());
+        assert_eq!(pending.len(), 7);
+    }
+
+    #[test]
+    fn corrupt_bmp_frames_are_errors() {
+        let mut frame = bmp_frame(2);
+        frame[0] = 2;
+        assert!(
+            next_bmp_frame(&mut BytesMut::from(&frame[..])).is_err(),
+            "version"
+        );
+
+        let mut frame = bmp_frame(2);
+        frame[1..5].copy_from_slice(&5u32.to_be_bytes());
+        assert!(
+            next_bmp_frame(&mut BytesMut::from(&frame[..])).is_err(),
+            "length"
+        );
+    }
+
+    #[test]
+    fn ris_messages_keep_their_host() {
+        let (host, elems) = decode_ris_message(&ris_update(Some("rrc21"), "203.0.113.0/24"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(host, "rrc21");
+        assert_eq!(elems.len(), 1);
+        assert_eq!(elems[0].prefix.to_string(), "203.0.113.0/24");
+    }
+
+    #[test]
+    fn ris_messages_without_updates_are_skipped() {
+        assert!(decode_ris_message(&ris_update(None, "203.0.113.0/24"))
+            .unwrap()
+            .is_none());
+        let error = r#"{"type": "ris_error", "data": {"message": "Unknown host"}}"#;
+        assert!(decode_ris_message(error).unwrap().is_none());
+        let keepalive = r#"{"type": "ris_message", "data": {"timestamp": 1697000000.0,
+            "peer": "192.0.2.1", "peer_asn": "64496", "id": "1", "host": "rrc21", "type": "KEEPALIVE"}}"#;
+        assert!(decode_ris_message(keepalive).unwrap().is_none());
+        assert!(decode_ris_message("not json").is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replayed_ris_live_reaches_the_store() {
+        let dir = std::env::temp_dir().join(format!("bgp_track-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let recording = dir.join("ris.jsonl");
+        let lines = [
+            ris_update(Some("rrc21"), "203.0.113.0/24"),
+            r#"{"type": "ris_subscribe_ok", "data": {}}"#.to_string(),
+            ris_update(Some("rrc00"), "198.51.100.0/24"),
+        ];
+        std::fs::write(&recording, lines.join("\n")).unwrap();
+
+        // a free port for the replay server
+        let bind = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string();
+        let replay = {
+            let (recording, bind) = (recording.clone(), bind.clone());
+            tokio::spawn(async move { replay_ris_live(&recording, &bind, Duration::ZERO).await })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let store = SqliteStore::open(&dir.join("bgp.sqlite")).await.unwrap();
+        let (sender, receiver) = writer_queue(1 << 20);
+        let writer = spawn_writers(store.clone(), 1, receiver, None);
+        let url = format!("ws://{bind}");
+        ris_live(&url, None, sender, CancellationToken::new())
+            .await
+            .unwrap();
+        writer.await.unwrap().unwrap();
+        replay.abort();
+
+        for ip in ["203.0.113.7", "198.51.100.7"] {
+            let found = store.ip_search(ip.parse().unwrap()).await.unwrap();
+            assert_eq!(found.len(), 1, "{ip}");
+            assert_eq!(found[0].asn, 64496);
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
@@ -0,0 +1,69 @@
+// Logs and Errors
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+
+// sqlx stuff
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::PgPool;
+
+// bag of tools
+use std::collections::HashMap;
+
+/// The migrations under `./migrations`, embedded at build time, `build.rs` rebuilds when they change
+pub(crate) static MIGRATOR: Migrator = sqlx::migrate!("./migrations");
+
+/// Applies every pending migration
+pub(crate) async fn up(pool: &PgPool) -> Result<()> {
+    warn!("Running migrations...");
+    MIGRATOR.run(pool).await.context("Failed while migrating")?;
+    info!("Schema is up to date");
+    Ok(())
+}
+
+/// Reverts the latest applied migration
+pub(crate) async fn down(pool: &PgPool) -> Result<()> {
+    let mut applied = applied(pool).await?.into_keys().collect::<Vec<_>>();
+    applied.sort_unstable();
+    let Some(latest) = applied.pop() else {
+        bail!("No migration to revert");
+    };
+    let target = applied.last().copied().unwrap_or(0);
+    warn!("Reverting migration {latest}...");
+    MIGRATOR
+        .undo(pool, target)
+        .await
+        .with_context(|| format!("Failed while reverting migration {latest}"))?;
+    info!("Reverted migration {latest}");
+    Ok(())
+}
+
+/// Logs every embedded migration as applied, pending or changed since it was applied
+pub(crate) async fn status(pool: &PgPool) -> Result<()> {
+    let applied = applied(pool).await?;
+    for migration in MIGRATOR
+        .iter()
+        .filter(|m| !m.migration_type.is_down_migration())
+    {
+        let status = match applied.get(&migration.version) {
+            None => "pending",
+            Some(checksum) if *checksum == migration.checksum => "applied",
+            Some(_) => "CHANGED since applied",
+        };
+        info!(
+            "{} {:<40} {status}",
+            migration.version, migration.description
+        );
+    }
+    Ok(())
+}
+
+async fn applied(pool: &PgPool) -> Result<HashMap<i64, std::borrow::Cow<'static, [u8]>>> {
+    let mut conn = pool.acquire().await?;
+    conn.ensure_migrations_table().await?;
+    Ok(conn
+        .list_applied_migrations()
+        .await?
+        .into_iter()
+        .map(|m| (m.version, m.checksum))
+        .collect())
+}
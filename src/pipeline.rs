@@ -0,0 +1,369 @@
+// Logs and Errors
+use anyhow::{anyhow, Result};
+use log::{debug, error, info};
+
+// storage
+use crate::db_writer::types::{ASPathSeg, Announcement};
+use crate::storage::Storage;
+
+// Data Processing
+use crate::bgp::FileReport;
+use crossbeam_channel::{Receiver, SendError, Sender, TrySendError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Messages from the parsers and live feeds to the writers, see [`spawn_writers`].
+/// The writers stop once every [`WriterSender`] is dropped.
+pub(crate) enum ToWriter {
+    /// An MRT file whose rows follow in batches while it is parsed, one writer stores all of them and the
+    /// checkpoint in a single transaction
+    File {
+        url: String,
+        collector: String,
+        batches: FileStream,
+    },
+    /// Rows from a live feed, there is no file to checkpoint
+    Rows {
+        collector: String,
+        rows: Vec<Announcement>,
+    },
+}
+
+/// What a parser streams for one file
+pub(crate) enum FileBatch {
+    Rows(Vec<Announcement>),
+    /// The download broke off and is retried, forget the rows sent so far
+    Restart,
+    /// The file is parsed, or failed to be when the report has an error
+    Done(FileReport),
+}
+
+/// When a parser hands its rows to the writer, whichever limit is reached first
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BatchSize {
+    pub(crate) rows: usize,
+    pub(crate) bytes: usize,
+}
+
+/// Rough heap and inline size of `rows`, what the [`ByteBudget`] is charged
+pub(crate) fn rows_bytes(rows: &[Announcement]) -> usize {
+    rows.iter()
+        .map(|a| {
+            std::mem::size_of::<Announcement>()
+                + a.as_path_segments.len() * std::mem::size_of::<ASPathSeg>()
+                + a.as_path_segments
+                    .iter()
+                    .map(|s| s.as_path.len() * 8)
+                    .sum::<usize>()
+        })
+        .sum()
+}
+
+/// Progress reported by the writers back to the parser, one message per file
+pub(crate) enum FromWriter {
+    Copied { url: String, rows: u64 },
+    Failed { url: String, error: String },
+}
+
+/// Caps the bytes of rows queued for or being written by the writers, senders block while it is used up.
+/// Keeps count of how often and how long senders blocked on it or on a writer, that is the backpressure.
+pub(crate) struct ByteBudget {
+    capacity: usize,
+    state: Mutex<BudgetState>,
+    freed: Condvar,
+}
+
+#[derive(Default)]
+struct BudgetState {
+    used: usize,
+    peak: usize,
+    waits: u64,
+    waited: Duration,
+}
+
+impl ByteBudget {
+    pub(crate) fn new(capacity: usize) -> Self {
+        ByteBudget {
+            capacity,
+            state: Mutex::new(BudgetState::default()),
+            freed: Condvar::new(),
+        }
+    }
+
+    /// Blocks until `bytes` fit, a message larger than the whole budget is let through when nothing else is queued
+    fn acquire(&self, bytes: usize) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.used > 0 && state.used + bytes > self.capacity {
+            let now = Instant::now();
+            state.waits += 1;
+            while state.used > 0 && state.used + bytes > self.capacity {
+                state = self.freed.wait(state).unwrap_or_else(|e| e.into_inner());
+            }
+            state.waited += now.elapsed();
+        }
+        state.used += bytes;
+        state.peak = state.peak.max(state.used);
+    }
+
+    /// A sender waited `waited` for a writer to take its batch
+    fn note_wait(&self, waited: Duration) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.waits += 1;
+        state.waited += waited;
+    }
+
+    fn release(&self, bytes: usize) {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.used -= bytes;
+        self.freed.notify_all();
+    }
+
+    fn log_stats(&self) {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        info!(
+            ">>> Write buffer peaked at {:.1} of {:.1} MB, senders waited {} times for {:.2?} in total",
+            state.peak as f64 / 1e6,
+            self.capacity as f64 / 1e6,
+            state.waits,
+            state.waited
+        );
+    }
+}
+
+/// Sending half of the writer queue, blocks while the [`ByteBudget`] is used up
+#[derive(Clone)]
+pub(crate) struct WriterSender {
+    sender: Sender<ToWriter>,
+    budget: Arc<ByteBudget>,
+}
+
+impl WriterSender {
+    pub(crate) fn send(&self, msg: ToWriter) -> Result<(), SendError<ToWriter>> {
+        let bytes = match &msg {
+            ToWriter::Rows { rows, .. } => rows_bytes(rows),
+            ToWriter::File { .. } => 0, // its batches are charged one by one
+        };
+        self.budget.acquire(bytes);
+        self.sender.send(msg).inspect_err(|_| self.budget.release(bytes))
+    }
+
+    /// Queues a file for the writers, its batches are sent on the returned [`FileSender`].
+    /// A single batch waits for the writer, so a parser holds at most two batches of rows.
+    pub(crate) fn open_file(&self, url: &str, collector: &str) -> Result<FileSender> {
+        let (sender, receiver) = crossbeam_channel::bounded(1);
+        self.sender
+            .send(ToWriter::File {
+                url: url.to_string(),
+                collector: collector.to_string(),
+                batches: FileStream {
+                    url: url.to_string(),
+                    receiver,
+                    budget: self.budget.clone(),
+                    held: 0,
+                },
+            })
+            .map_err(|_| anyhow!("Channel Disconnected, writers are gone"))?;
+        Ok(FileSender {
+            sender,
+            budget: self.budget.clone(),
+        })
+    }
+}
+
+/// Sends the batches of one file, see [`WriterSender::open_file`]
+pub(crate) struct FileSender {
+    sender: Sender<FileBatch>,
+    budget: Arc<ByteBudget>,
+}
+
+impl FileSender {
+    /// Fails when the writer gave up on the file
+    pub(crate) fn send(&self, batch: FileBatch) -> Result<()> {
+        let bytes = match &batch {
+            FileBatch::Rows(rows) => rows_bytes(rows),
+            _ => 0,
+        };
+        self.budget.acquire(bytes);
+        let batch = match self.sender.try_send(batch) {
+            Ok(()) => return Ok(()),
+            Err(TrySendError::Full(batch)) => batch,
+            Err(TrySendError::Disconnected(_)) => {
+                self.budget.release(bytes);
+                return Err(anyhow!("Writer gave up on the file"));
+            }
+        };
+        let now = Instant::now();
+        let sent = self.sender.send(batch);
+        self.budget.note_wait(now.elapsed());
+        sent.map_err(|_| {
+            self.budget.release(bytes);
+            anyhow!("Writer gave up on the file")
+        })
+    }
+}
+
+/// Receiving end of one file's batches, handed to [`Storage::write_file`]
+pub(crate) struct FileStream {
+    url: String,
+    receiver: Receiver<FileBatch>,
+    budget: Arc<ByteBudget>,
+    held: usize,
+}
+
+impl FileStream {
+    /// The MRT file the batches come from
+    pub(crate) fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Next batch of the file, the previous one counts as written.
+    /// `None` when the parser went away without finishing the file.
+    pub(crate) fn next(&mut self) -> Option<FileBatch> {
+        self.budget.release(std::mem::take(&mut self.held));
+        // waiting on the parser blocks, keep that off the async workers
+        let batch = tokio::task::block_in_place(|| self.receiver.recv()).ok()?;
+        if let FileBatch::Rows(rows) = &batch {
+            self.held = rows_bytes(rows);
+        }
+        Some(batch)
+    }
+}
+
+impl Drop for FileStream {
+    fn drop(&mut self) {
+        self.budget.release(self.held);
+    }
+}
+
+/// Receiving half of the writer queue, see [`spawn_writers`]
+pub(crate) struct WriterReceiver {
+    receiver: Receiver<ToWriter>,
+    budget: Arc<ByteBudget>,
+}
+
+/// A queue to the writers holding at most `budget` bytes of rows
+pub(crate) fn writer_queue(budget: usize) -> (WriterSender, WriterReceiver) {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let budget = Arc::new(ByteBudget::new(budget));
+    (
+        WriterSender {
+            sender,
+            budget: budget.clone(),
+        },
+        WriterReceiver { receiver, budget },
+    )
+}
+
+/// Spawns `writers` tasks draining `queue` into `store`, one [`Storage::write_file`] or [`Storage::write_batch`]
+/// per message, so with Postgres every writer copies over a connection of its own. Every file's outcome is recorded in the store and
+/// reported on `progress`, if anyone is listening. The returned task ends once every sender is dropped and
+/// everything queued is written.
+pub(crate) fn spawn_writers<S: Storage>(
+    store: S,
+    writers: usize,
+    queue: WriterReceiver,
+    progress: Option<Sender<FromWriter>>,
+) -> tokio::task::JoinHandle<Result<()>> {
+    tokio::task::spawn(async move {
+        let mut tasks = tokio::task::JoinSet::new();
+        for writer in 0..writers.max(1) {
+            let store = store.clone();
+            let receiver = queue.receiver.clone();
+            let budget = queue.budget.clone();
+            let progress = progress.clone();
+            tasks.spawn(async move {
+                // waiting on the queue blocks, keep that off the async workers
+                while let Ok(msg) = tokio::task::block_in_place(|| receiver.recv()) {
+                    let bytes = match &msg {
+                        ToWriter::Rows { rows, .. } => rows_bytes(rows),
+                        ToWriter::File { .. } => 0,
+                    };
+                    write(&store, writer, msg, &progress).await;
+                    budget.release(bytes);
+                }
+            });
+        }
+        drop(progress);
+
+        let mut result = Ok(());
+        while let Some(res) = tasks.join_next().await {
+            if let Err(e) = res {
+                result = Err(anyhow!("A writer panicked, {e}"));
+            }
+        }
+        queue.budget.log_stats();
+        result
+    })
+}
+
+async fn write<S: Storage>(
+    store: &S,
+    writer: usize,
+    msg: ToWriter,
+    progress: &Option<Sender<FromWriter>>,
+) {
+    match msg {
+        ToWriter::File {
+            url,
+            collector,
+            batches,
+        } => {
+            let now = Instant::now();
+            match store.write_file(&collector, batches).await {
+                Ok((report, rows)) if report.error.is_none() => {
+                    info!(
+                        ">>> Writer {writer} copied {rows} rows of {url} in: {:.2?}",
+                        now.elapsed()
+                    );
+                    notify(progress, FromWriter::Copied { url, rows });
+                }
+                Ok((report, _)) => record_failure(store, progress, report).await,
+                Err(e) => {
+                    error!(">>> Error while copying {url}, {e:#}");
+                    let report = FileReport {
+                        url,
+                        collector,
+                        rows: 0,
+                        corrupt_records: 0,
+                        error: Some(format!("could not copy into db, {e:#}")),
+                    };
+                    record_failure(store, progress, report).await;
+                }
+            }
+        }
+        ToWriter::Rows { collector, rows } => {
+            let now = Instant::now();
+            match store.write_batch(&collector, rows).await {
+                Ok(rows) => debug!(
+                    ">>> Writer {writer} copied {rows} live rows in: {:.2?}",
+                    now.elapsed()
+                ),
+                Err(e) => error!(">>> Error while copying live rows, {e:#}"),
+            }
+        }
+    }
+}
+
+async fn record_failure<S: Storage>(
+    store: &S,
+    progress: &Option<Sender<FromWriter>>,
+    report: FileReport,
+) {
+    if let Err(e) = store.record_failure(&report).await {
+        error!(">>> Could not record failure of {}, {e:#}", report.url);
+    }
+    notify(
+        progress,
+        FromWriter::Failed {
+            url: report.url,
+            error: report.error.unwrap_or_default(),
+        },
+    );
+}
+
+fn notify(progress: &Option<Sender<FromWriter>>, event: FromWriter) {
+    if let Some(progress) = progress {
+        // the parser may have given up listening, the db is the record that matters
+        let _ = progress.send(event);
+    }
+}
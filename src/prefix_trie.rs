@@ -0,0 +1,318 @@
+// types
+use ipnetwork::IpNetwork;
+use std::fmt;
+
+/// How an announced prefix relates to a listed one
+#[derive(
+    clap::ValueEnum, serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash,
+)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum MatchKind {
+    /// The very same prefix
+    Exact,
+    /// Announced inside a listed prefix, a /24 out of a listed /16
+    CoveredBy,
+    /// Announced around a listed prefix, a /16 holding a listed /24
+    Covers,
+}
+
+/// A listed prefix an announced one matched, and how
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PrefixMatch {
+    pub(crate) kind: MatchKind,
+    pub(crate) listed: IpNetwork,
+}
+
+impl fmt::Display for PrefixMatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            MatchKind::Exact => write!(f, "{} exactly", self.listed),
+            MatchKind::CoveredBy => write!(f, "covered by {}", self.listed),
+            MatchKind::Covers => write!(f, "covering {}", self.listed),
+        }
+    }
+}
+
+struct Node<T> {
+    children: [Option<Box<Node<T>>>; 2],
+    value: Option<(IpNetwork, T)>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Node {
+            children: [None, None],
+            value: None,
+        }
+    }
+}
+
+/// Binary trie of IPv4 and IPv6 prefixes, one bit per level
+pub(crate) struct PrefixTrie<T> {
+    v4: Node<T>,
+    v6: Node<T>,
+    len: usize,
+}
+
+impl<T> Default for PrefixTrie<T> {
+    fn default() -> Self {
+        PrefixTrie {
+            v4: Node::default(),
+            v6: Node::default(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> PrefixTrie<T> {
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// The value stored for `prefix`, host bits are ignored
+    pub(crate) fn entry(&mut self, prefix: IpNetwork) -> &mut T
+    where
+        T: Default,
+    {
+        let (key, len) = key(&prefix);
+        let mut node = match prefix {
+            IpNetwork::V4(_) => &mut self.v4,
+            IpNetwork::V6(_) => &mut self.v6,
+        };
+        for depth in 0..len {
+            node = &mut **node.children[bit(key, depth)].get_or_insert_with(Box::default);
+        }
+        if node.value.is_none() {
+            self.len += 1;
+        }
+        let network = IpNetwork::new(prefix.network(), prefix.prefix()).unwrap_or(prefix);
+        &mut node.value.get_or_insert_with(|| (network, T::default())).1
+    }
+
+    /// Every listed prefix `prefix` matches in one of `kinds`, least specific first
+    pub(crate) fn matches(
+        &self,
+        prefix: &IpNetwork,
+        kinds: &[MatchKind],
+    ) -> Vec<(PrefixMatch, &T)> {
+        let (key, len) = key(prefix);
+        let mut node = match prefix {
+            IpNetwork::V4(_) => &self.v4,
+            IpNetwork::V6(_) => &self.v6,
+        };
+        let mut found = vec![];
+        for depth in 0..len {
+            if kinds.contains(&MatchKind::CoveredBy) {
+                found.extend(
+                    node.value
+                        .as_ref()
+                        .map(|v| found_as(MatchKind::CoveredBy, v)),
+                );
+            }
+            match &node.children[bit(key, depth)] {
+                Some(child) => node = &**child,
+                None => return found,
+            }
+        }
+        if kinds.contains(&MatchKind::Exact) {
+            found.extend(node.value.as_ref().map(|v| found_as(MatchKind::Exact, v)));
+        }
+        if kinds.contains(&MatchKind::Covers) {
+            let mut covered = vec![];
+            let mut stack: Vec<&Node<T>> = node.children.iter().flatten().map(|c| &**c).collect();
+            while let Some(node) = stack.pop() {
+                covered.extend(node.value.as_ref().map(|v| found_as(MatchKind::Covers, v)));
+                stack.extend(node.children.iter().flatten().map(|c| &**c));
+            }
+            covered.sort_by_key(|(m, _)| (m.listed.prefix(), m.listed.network()));
+            found.extend(covered);
+        }
+        found
+    }
+}
+
+fn found_as<T>(kind: MatchKind, (listed, value): &(IpNetwork, T)) -> (PrefixMatch, &T) {
+    (
+        PrefixMatch {
+            kind,
+            listed: *listed,
+        },
+        value,
+    )
+}
+
+/// The prefix's bits from the top of a u128 and how many of them count
+fn key(prefix: &IpNetwork) -> (u128, u8) {
+    match prefix {
+        IpNetwork::V4(net) => ((u32::from(net.network()) as u128) << 96, net.prefix()),
+        IpNetwork::V6(net) => (u128::from(net.network()), net.prefix()),
+    }
+}
+
+fn bit(key: u128, depth: u8) -> usize {
+    ((key >> (127 - depth)) & 1) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn net(s: &str) -> IpNetwork {
+        s.parse().unwrap()
+    }
+
+    fn trie(prefixes: &[&str]) -> PrefixTrie<&'static str> {
+        let mut trie = PrefixTrie::default();
+        for prefix in prefixes {
+            *trie.entry(net(prefix)) = "listed";
+        }
+        trie
+    }
+
+    fn matches(
+        trie: &PrefixTrie<&str>,
+        prefix: &str,
+        kinds: &[MatchKind],
+    ) -> Vec<(MatchKind, IpNetwork)> {
+        trie.matches(&net(prefix), kinds)
+            .into_iter()
+            .map(|(m, _)| (m.kind, m.listed))
+            .collect()
+    }
+
+    const ALL: [MatchKind; 3] = [MatchKind::Exact, MatchKind::CoveredBy, MatchKind::Covers];
+
+    #[test]
+    fn entry_ignores_host_bits_and_counts_prefixes_once() {
+        let mut trie = PrefixTrie::<u32>::default();
+        *trie.entry(net("10.1.2.3/16")) += 1;
+        *trie.entry(net("10.1.0.0/16")) += 1;
+        assert_eq!(trie.len(), 1);
+        let found = trie.matches(&net("10.1.0.0/16"), &[MatchKind::Exact]);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].0.listed, net("10.1.0.0/16"));
+        assert_eq!(*found[0].1, 2);
+    }
+
+    #[test]
+    fn nested_ipv4_least_specific_first() {
+        let trie = trie(&[
+            "10.0.0.0/8",
+            "10.1.0.0/16",
+            "10.1.1.0/24",
+            "10.1.2.0/24",
+            "10.1.2.128/25",
+        ]);
+        assert_eq!(
+            matches(&trie, "10.1.0.0/16", &ALL),
+            vec![
+                (MatchKind::CoveredBy, net("10.0.0.0/8")),
+                (MatchKind::Exact, net("10.1.0.0/16")),
+                (MatchKind::Covers, net("10.1.1.0/24")),
+                (MatchKind::Covers, net("10.1.2.0/24")),
+                (MatchKind::Covers, net("10.1.2.128/25")),
+            ]
+        );
+        assert_eq!(
+            matches(&trie, "10.1.2.0/23", &[MatchKind::Covers]),
+            vec![
+                (MatchKind::Covers, net("10.1.2.0/24")),
+                (MatchKind::Covers, net("10.1.2.128/25")),
+            ]
+        );
+        assert_eq!(matches(&trie, "11.0.0.0/8", &ALL), vec![]);
+    }
+
+    #[test]
+    fn only_asked_kinds() {
+        let trie = trie(&["10.0.0.0/8", "10.1.0.0/16", "10.1.1.0/24"]);
+        assert_eq!(
+            matches(&trie, "10.1.0.0/16", &[MatchKind::Exact]),
+            vec![(MatchKind::Exact, net("10.1.0.0/16"))]
+        );
+        assert_eq!(
+            matches(&trie, "10.1.0.0/16", &[MatchKind::CoveredBy]),
+            vec![(MatchKind::CoveredBy, net("10.0.0.0/8"))]
+        );
+        assert_eq!(matches(&trie, "10.1.0.0/16", &[]), vec![]);
+    }
+
+    #[test]
+    fn default_routes() {
+        let trie = trie(&["0.0.0.0/0", "192.0.2.0/24", "::/0"]);
+        assert_eq!(
+            matches(&trie, "192.0.2.0/24", &ALL),
+            vec![
+                (MatchKind::CoveredBy, net("0.0.0.0/0")),
+                (MatchKind::Exact, net("192.0.2.0/24")),
+            ]
+        );
+        assert_eq!(
+            matches(&trie, "0.0.0.0/0", &ALL),
+            vec![
+                (MatchKind::Exact, net("0.0.0.0/0")),
+                (MatchKind::Covers, net("192.0.2.0/24")),
+            ]
+        );
+        assert_eq!(
+            matches(&trie, "2001:db8::/32", &ALL),
+            vec![(MatchKind::CoveredBy, net("::/0"))]
+        );
+    }
+
+    #[test]
+    fn host_routes() {
+        let trie = trie(&["192.0.2.0/24", "192.0.2.1/32", "2001:db8::1/128"]);
+        assert_eq!(
+            matches(&trie, "192.0.2.1/32", &ALL),
+            vec![
+                (MatchKind::CoveredBy, net("192.0.2.0/24")),
+                (MatchKind::Exact, net("192.0.2.1/32")),
+            ]
+        );
+        assert_eq!(matches(&trie, "192.0.2.2/32", &[MatchKind::Exact]), vec![]);
+        assert_eq!(
+            matches(&trie, "2001:db8::/64", &ALL),
+            vec![(MatchKind::Covers, net("2001:db8::1/128"))]
+        );
+        assert_eq!(
+            matches(&trie, "2001:db8::1/128", &ALL),
+            vec![(MatchKind::Exact, net("2001:db8::1/128"))]
+        );
+    }
+
+    #[test]
+    fn families_do_not_mix() {
+        // ::ffff:192.0.2.0/120 holds the same bits as 192.0.2.0/24 mapped into IPv6
+        let trie = trie(&["192.0.2.0/24", "::ffff:c000:200/120"]);
+        assert_eq!(
+            matches(&trie, "192.0.2.0/24", &ALL),
+            vec![(MatchKind::Exact, net("192.0.2.0/24"))]
+        );
+        assert_eq!(
+            matches(&trie, "::ffff:c000:200/120", &ALL),
+            vec![(MatchKind::Exact, net("::ffff:c000:200/120"))]
+        );
+    }
+
+    #[test]
+    fn nested_ipv6() {
+        let trie = trie(&["2001:db8::/32", "2001:db8:1::/48", "2001:db8:1:2::/64"]);
+        assert_eq!(
+            matches(&trie, "2001:db8:1::/48", &ALL),
+            vec![
+                (MatchKind::CoveredBy, net("2001:db8::/32")),
+                (MatchKind::Exact, net("2001:db8:1::/48")),
+                (MatchKind::Covers, net("2001:db8:1:2::/64")),
+            ]
+        );
+        assert_eq!(
+            matches(&trie, "2001:db8:1:2:3::/80", &ALL),
+            vec![
+                (MatchKind::CoveredBy, net("2001:db8::/32")),
+                (MatchKind::CoveredBy, net("2001:db8:1::/48")),
+                (MatchKind::CoveredBy, net("2001:db8:1:2::/64")),
+            ]
+        );
+    }
+}
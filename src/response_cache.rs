@@ -0,0 +1,113 @@
+// Logs and Errors
+use anyhow::{Context, Result};
+use log::{debug, warn};
+
+// bag of tools
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::Duration;
+use time::OffsetDateTime;
+
+/// How a [`ResponseCache`] is used
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CacheMode {
+    /// Answer from the cache while fresh, ask the API otherwise
+    Normal,
+    /// Only ever answer from the cache, however old, never ask the API
+    Offline,
+    /// Always ask the API and replace what is cached
+    Refresh,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// Unix seconds
+    fetched_at: i64,
+    body: serde_json::Value,
+}
+
+/// Raw API responses on disk, one JSON file per request, so analysing the same window again costs no quota.
+/// Keys are the request without the token, e.g. `asns/13335`.
+pub(crate) struct ResponseCache {
+    dir: PathBuf,
+    ttl: Duration,
+    mode: CacheMode,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(dir: PathBuf, ttl: Duration, mode: CacheMode) -> Self {
+        ResponseCache { dir, ttl, mode }
+    }
+
+    pub(crate) fn mode(&self) -> CacheMode {
+        self.mode
+    }
+
+    /// The cached response for `key`, `None` when there is none or it expired. Offline, expired responses are
+    /// still answered and a refresh never finds anything.
+    pub(crate) async fn get(&self, key: &str) -> Option<serde_json::Value> {
+        if self.mode == CacheMode::Refresh {
+            return None;
+        }
+        let path = self.path(key);
+        let bytes = match tokio::fs::read(&path).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("Could not read cached {key}, {e}");
+                return None;
+            }
+        };
+        let entry: Entry = match serde_json::from_slice(&bytes) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Ignoring corrupt cache entry {}, {e}", path.display());
+                return None;
+            }
+        };
+        let age = OffsetDateTime::now_utc().unix_timestamp() - entry.fetched_at;
+        if self.mode == CacheMode::Normal && age > self.ttl.as_secs() as i64 {
+            debug!(
+                "Cached {key} expired {}s ago",
+                age - self.ttl.as_secs() as i64
+            );
+            return None;
+        }
+        debug!("Answering {key} from the cache, {age}s old");
+        Some(entry.body)
+    }
+
+    /// Stores `body` as the response for `key`, written aside and renamed so readers never see half of it
+    pub(crate) async fn put(&self, key: &str, body: &serde_json::Value) -> Result<()> {
+        let entry = Entry {
+            fetched_at: OffsetDateTime::now_utc().unix_timestamp(),
+            body: body.clone(),
+        };
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("Failed while creating cache dir {}", self.dir.display()))?;
+        let path = self.path(key);
+        let tmp = path.with_extension(format!("{}.tmp", uuid::Uuid::new_v4()));
+        tokio::fs::write(&tmp, serde_json::to_vec(&entry)?)
+            .await
+            .with_context(|| format!("Failed while writing {}", tmp.display()))?;
+        tokio::fs::rename(&tmp, &path)
+            .await
+            .with_context(|| format!("Failed while writing {}", path.display()))?;
+        Ok(())
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let name: String = key
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        self.dir.join(format!("{name}.json"))
+    }
+}
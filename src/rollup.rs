@@ -0,0 +1,107 @@
+// types
+use crate::db_writer::types::Announcement;
+use ipnetwork::IpNetwork;
+
+// bag of tools
+use serde::Serialize;
+use std::collections::HashMap;
+
+const HOUR: i64 = 3600;
+const DAY: i64 = 86_400;
+
+/// Announce and withdraw counts of a prefix and origin in the bucket starting at `start`.
+/// Withdrawals carry no path, they are counted for origin 0.
+#[derive(Serialize, Debug)]
+pub(crate) struct PrefixCount {
+    pub(crate) start: i64,
+    pub(crate) prefix: IpNetwork,
+    pub(crate) origin: i64,
+    pub(crate) announcements: i64,
+    pub(crate) withdrawals: i64,
+}
+
+/// Update volume of a peer in the hour starting at `start`
+#[derive(Serialize, Debug)]
+pub(crate) struct PeerCount {
+    pub(crate) start: i64,
+    pub(crate) peer_asn: i64,
+    pub(crate) announcements: i64,
+    pub(crate) withdrawals: i64,
+}
+
+/// The summaries a batch of rows adds to the rollup tables, they outlive the raw announcements.
+/// Counts are additive, so a store adds them to what it has; per origin prefix counts are the
+/// number of `prefix_daily` rows of that origin and day.
+#[derive(Serialize, Debug, Default)]
+pub(crate) struct Rollup {
+    pub(crate) prefix_hourly: Vec<PrefixCount>,
+    pub(crate) prefix_daily: Vec<PrefixCount>,
+    pub(crate) peer_hourly: Vec<PeerCount>,
+}
+
+impl Rollup {
+    pub(crate) fn of<'a>(rows: impl IntoIterator<Item = &'a Announcement>) -> Rollup {
+        let mut prefix_hourly: HashMap<(i64, IpNetwork, i64), (i64, i64)> = HashMap::new();
+        let mut prefix_daily: HashMap<(i64, IpNetwork, i64), (i64, i64)> = HashMap::new();
+        let mut peer_hourly: HashMap<(i64, i64), (i64, i64)> = HashMap::new();
+        for row in rows {
+            let ts = row.timestamp.unix_timestamp();
+            let (hour, day) = (ts.div_euclid(HOUR) * HOUR, ts.div_euclid(DAY) * DAY);
+            let count = |counts: &mut (i64, i64)| match row.withdrawal {
+                false => counts.0 += 1,
+                true => counts.1 += 1,
+            };
+            count(
+                prefix_hourly
+                    .entry((hour, row.prefix, origin(row)))
+                    .or_default(),
+            );
+            count(
+                prefix_daily
+                    .entry((day, row.prefix, origin(row)))
+                    .or_default(),
+            );
+            count(peer_hourly.entry((hour, row.asn)).or_default());
+        }
+
+        let prefix_counts =
+            |counts: HashMap<(i64, IpNetwork, i64), (i64, i64)>| -> Vec<PrefixCount> {
+                counts
+                    .into_iter()
+                    .map(
+                        |((start, prefix, origin), (announcements, withdrawals))| PrefixCount {
+                            start,
+                            prefix,
+                            origin,
+                            announcements,
+                            withdrawals,
+                        },
+                    )
+                    .collect()
+            };
+        Rollup {
+            prefix_hourly: prefix_counts(prefix_hourly),
+            prefix_daily: prefix_counts(prefix_daily),
+            peer_hourly: peer_hourly
+                .into_iter()
+                .map(
+                    |((start, peer_asn), (announcements, withdrawals))| PeerCount {
+                        start,
+                        peer_asn,
+                        announcements,
+                        withdrawals,
+                    },
+                )
+                .collect(),
+        }
+    }
+}
+
+/// Last AS of the path, 0 when there is none
+fn origin(row: &Announcement) -> i64 {
+    row.as_path_segments
+        .last()
+        .and_then(|seg| seg.as_path.last())
+        .copied()
+        .unwrap_or(0)
+}
@@ -1,98 +1,821 @@
-use std::collections::HashSet;
-
-use std::str::FromStr;
+use std::collections::HashMap;
 // errors, logs, tools, etc
-use anyhow::{Result};
+use anyhow::{anyhow, Context, Result};
 
 use ipnetwork::IpNetwork;
 use itertools::Itertools;
 
-use log::{debug, error, trace, warn};
-use reqwest::Client;
-use serde_json::json;
+use log::{debug, trace, warn};
+use futures::future::join_all;
+use reqwest::{Client, StatusCode};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
 
+use crate::intel::{Findings, Query, ThreatIntel, Verdict};
+use crate::prefix_trie::{MatchKind, PrefixMatch, PrefixTrie};
+use crate::response_cache::{CacheMode, ResponseCache};
+use models::{AsnRecord, NetRecord};
 
-lazy_static::lazy_static! {
-    static ref SECLYTICS_API_TOKEN: String = {
-        use dotenvy::{dotenv};
-        dotenv().expect(".env file not found");
-        dotenvy::var("SECLYTICS_API_TOKEN").expect("SECLYTICS_API_TOKEN not found")
-    };
-    static ref SECLYTICS_API_ENDPOINT: String = {
-        use dotenvy::{dotenv};
-        dotenv().expect(".env file not found");
-        dotenvy::var("SECLYTICS_API_ENDPOINT").expect("SECLYTICS_API_ENDPOINT not found")
-    };
+
+/// How sure a listing is when Seclytics gives no score for it
+const CONFIDENCE: f64 = 0.8;
+const SOURCE: &str = "seclytics";
+/// First wait before retrying, doubled on every attempt
+const BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// How hard the API is pushed
+#[derive(Clone, Debug)]
+pub(crate) struct ApiOptions {
+    /// Where the API is, e.g. a local mock server. Only needed when the cache is not enough.
+    pub(crate) endpoint: Option<String>,
+    pub(crate) token: Option<String>,
+    /// Requests started per second across all lookups, 0 for no limit
+    pub(crate) requests_per_second: f64,
+    /// Attempts after the first on a 429, a 5xx or a broken connection
+    pub(crate) retries: u32,
+    /// Ids asked for per request
+    pub(crate) batch_size: usize,
+}
+
+/// Spaces requests evenly, each caller waits for the next free slot
+struct RateLimit {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl RateLimit {
+    fn new(per_second: f64) -> Self {
+        RateLimit {
+            interval: if per_second > 0.0 {
+                Duration::from_secs_f64(1.0 / per_second)
+            } else {
+                Duration::ZERO
+            },
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn wait(&self) {
+        let mut next = self.next.lock().await;
+        let at = (*next).max(Instant::now());
+        *next = at + self.interval;
+        drop(next);
+        tokio::time::sleep_until(at).await;
+    }
+}
+
+/// The Seclytics API as a [`ThreatIntel`] provider, asked in bulk for records that are not cached.
+/// Lookups may run concurrently, they share the rate limit.
+pub(crate) struct Seclytics {
+    client: Client,
+    cache: ResponseCache,
+    endpoint: Option<String>,
+    token: Option<String>,
+    limit: RateLimit,
+    retries: u32,
+    batch_size: usize,
+    matching: Vec<MatchKind>,
 }
-pub(crate) async fn asn_is_malicious(
-    asn: i64,
-    cidr: Vec<IpNetwork>,
-    client: &Client,
-) -> Result<(usize, bool)> {
-    let data: serde_json::Value = client
-        .get(url(
-            &*("asns/".to_string() + &*asn.to_string()),
-            [
-                // ("ids".to_string(), &*asn.iter().map(|x| x.0.to_string()).join(",")),
-                ("access_token".to_string(), ""),
-            ],
-        )?)
-        .send()
-        .await?
-        .json()
-        .await?;
-
-    let mal_asn = if let Some(categories) = data["global_threat_context"]["categories"].as_array() {
-        categories.contains(&json!("malicious"))
-    } else {
-        false
+
+impl Seclytics {
+    /// Announced prefixes are bad when they match a known bad cidr in one of the `matching` kinds
+    pub(crate) fn new(
+        client: Client,
+        cache: ResponseCache,
+        options: ApiOptions,
+        matching: &[MatchKind],
+    ) -> Self {
+        Seclytics {
+            client,
+            cache,
+            endpoint: options.endpoint,
+            token: options.token,
+            limit: RateLimit::new(options.requests_per_second),
+            retries: options.retries,
+            batch_size: options.batch_size.max(1),
+            matching: matching.to_vec(),
+        }
+    }
+
+    /// Records of `ids` from the `kind` bulk endpoint, `asns/` or `cidrs/`, in batches of [`ApiOptions::batch_size`].
+    /// Cached records, and cached answers that there is none, are not asked for again. An id is `Ok(None)` when
+    /// Seclytics has no record of it, and an error when it could not be looked up, offline that is when it is not
+    /// cached.
+    async fn get_many(
+        &self,
+        kind: &str,
+        ids: &[String],
+    ) -> HashMap<String, Result<Option<serde_json::Value>>> {
+        let mut records = HashMap::new();
+        let mut missing = vec![];
+        for id in ids.iter().unique() {
+            match self.cache.get(&format!("{kind}{id}")).await {
+                // Seclytics had no record of it when asked
+                Some(serde_json::Value::Null) => {
+                    records.insert(id.clone(), Ok(None));
+                }
+                Some(body) => {
+                    records.insert(id.clone(), Ok(Some(body)));
+                }
+                None => missing.push(id.clone()),
+            }
+        }
+        if self.cache.mode() == CacheMode::Offline {
+            if !missing.is_empty() {
+                warn!("{} {kind} are not cached, skipping them offline", missing.len());
+            }
+            records.extend(missing.into_iter().map(|id| {
+                let error = anyhow!("{kind}{id} is not cached and lookups are offline");
+                (id, Err(error))
+            }));
+            return records;
+        }
+
+        let batches = missing.chunks(self.batch_size).map(|batch| async move {
+            let answer = self.fetch(kind, &batch.join(",")).await.and_then(split_bulk);
+            (batch, answer)
+        });
+        for (batch, answer) in join_all(batches).await {
+            match answer {
+                Ok(mut found) => {
+                    for id in batch {
+                        let body = found.remove(id);
+                        // no record is cached as null, so it is not asked for again either
+                        let cached = body.as_ref().unwrap_or(&serde_json::Value::Null);
+                        if let Err(e) = self.cache.put(&format!("{kind}{id}"), cached).await {
+                            warn!("Could not cache {kind}{id}, {e:#}");
+                        }
+                        records.insert(id.clone(), Ok(body));
+                    }
+                }
+                Err(e) => {
+                    warn!("Could not look up {} {kind}, {e:#}", batch.len());
+                    for id in batch {
+                        records.insert(id.clone(), Err(anyhow!("{e:#}")));
+                    }
+                }
+            }
+        }
+        records
+    }
+
+    /// Asks the API for the comma separated `ids`, retrying with exponential backoff while it is overloaded
+    /// or unreachable. A `Retry-After` the API sends is waited instead.
+    async fn fetch(&self, path: &str, ids: &str) -> Result<serde_json::Value> {
+        let (Some(endpoint), Some(token)) = (&self.endpoint, &self.token) else {
+            return Err(anyhow!("Seclytics endpoint or api token is not set"));
+        };
+        let ids: String = url::form_urlencoded::byte_serialize(ids.as_bytes()).collect();
+        let url = url(endpoint, token, path, &[("ids", &ids)]);
+        let mut attempt = 0;
+        loop {
+            self.limit.wait().await;
+            let (error, retry_after) = match self.client.get(&url).send().await {
+                Ok(response) if response.status().is_success() => {
+                    return response
+                        .json()
+                        .await
+                        .map_err(|e| anyhow!("Seclytics answered {path} with something else than JSON, {e}"));
+                }
+                Ok(response)
+                    if response.status() == StatusCode::TOO_MANY_REQUESTS
+                        || response.status().is_server_error() =>
+                {
+                    let retry_after = response
+                        .headers()
+                        .get(reqwest::header::RETRY_AFTER)
+                        .and_then(|v| v.to_str().ok()?.parse().ok())
+                        .map(Duration::from_secs);
+                    (anyhow!("Seclytics answered {} for {path}", response.status()), retry_after)
+                }
+                Ok(response) => {
+                    return Err(anyhow!("Seclytics answered {} for {path}", response.status()))
+                }
+                Err(e) if e.is_timeout() || e.is_connect() || e.is_request() => {
+                    (anyhow!(e).context(format!("Failed while asking Seclytics for {path}")), None)
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if attempt >= self.retries {
+                return Err(error.context(format!("Gave up after {} attempts", attempt + 1)));
+            }
+            let backoff = retry_after
+                .unwrap_or(BACKOFF * 2u32.saturating_pow(attempt))
+                .min(MAX_BACKOFF);
+            debug!("{error:#}, retrying in {backoff:.1?}");
+            tokio::time::sleep(backoff).await;
+            attempt += 1;
+        }
+    }
+
+    /// The ASN's own verdict, and a prefix is bad when it matches one of the cidrs Seclytics relates to the ASN
+    fn asn_findings(&self, asn: i64, record: &AsnRecord, prefixes: &[IpNetwork]) -> Findings {
+        let Some(context) = &record.global_threat_context else {
+            warn!("Could not find data for AS{asn}");
+            return Findings::default();
+        };
+        let mut known_bad = PrefixTrie::<()>::default();
+        let mut errors = vec![];
+        for cidr in &context.cidrs {
+            match cidr {
+                Ok(cidr) => *known_bad.entry(*cidr) = (),
+                Err(entry) => errors.push(anyhow!("Malformed Seclytics cidr {entry} for AS{asn}")),
+            }
+        }
+        debug!("{} known bad cidrs for AS{asn}", known_bad.len());
+
+        Findings {
+            asn: context.verdict(&format!("AS{asn}")),
+            prefixes: prefixes
+                .iter()
+                .filter_map(|prefix| {
+                    let matches = known_bad
+                        .matches(prefix, &self.matching)
+                        .into_iter()
+                        .map(|(m, _)| m)
+                        .collect_vec();
+                    if matches.is_empty() {
+                        return None;
+                    }
+                    let verdict = Verdict {
+                        malicious: true,
+                        confidence: context.confidence(),
+                        source: SOURCE.to_string(),
+                        reasons: matches
+                            .iter()
+                            .map(|m| format!("known bad cidr of AS{asn}, {m}"))
+                            .collect(),
+                        matches,
+                    };
+                    Some((*prefix, verdict))
+                })
+                .collect(),
+            errors,
+        }
+    }
+}
+
+impl ThreatIntel for Seclytics {
+    async fn lookup(&self, asn: i64, prefixes: &[IpNetwork]) -> Result<Findings> {
+        let query = Query {
+            asn,
+            prefixes: prefixes.to_vec(),
+        };
+        self.lookup_many(&[query])
+            .await
+            .pop()
+            .unwrap_or_else(|| Ok(Findings::default()))
+    }
+
+    /// Every ASN and every announced prefix goes out in bulk requests, single hosts to the ip endpoint.
+    /// A prefix Seclytics categorises as malicious itself is bad too, a prefix that could not be looked up
+    /// only leaves that out. A malformed ASN record leaves the ASN unknown.
+    async fn lookup_many(&self, queries: &[Query]) -> Vec<Result<Findings>> {
+        let asns = queries.iter().map(|q| q.asn.to_string()).collect_vec();
+        let (hosts, cidrs): (Vec<&IpNetwork>, Vec<&IpNetwork>) = queries
+            .iter()
+            .flat_map(|q| q.prefixes.iter())
+            .partition(|p| is_host(p));
+        let hosts = hosts.iter().map(|p| p.ip().to_string()).collect_vec();
+        let cidrs = cidrs.iter().map(|p| p.to_string()).collect_vec();
+        let (mut asn_records, ip_records, cidr_records) = tokio::join!(
+            self.get_many("asns/", &asns),
+            self.get_many("ips/", &hosts),
+            self.get_many("cidrs/", &cidrs)
+        );
+
+        let mut answers = Vec::with_capacity(queries.len());
+        for query in queries {
+            let record = match asn_records.remove(&query.asn.to_string()) {
+                Some(Ok(Some(data))) => serde_json::from_value::<AsnRecord>(data)
+                    .with_context(|| format!("Malformed Seclytics record for AS{}", query.asn)),
+                Some(Ok(None)) | None => Ok(AsnRecord::default()),
+                Some(Err(e)) => Err(e),
+            };
+            let mut findings = match record {
+                Ok(record) => self.asn_findings(query.asn, &record, &query.prefixes),
+                Err(e) => {
+                    answers.push(Err(e));
+                    continue;
+                }
+            };
+            if !self.matching.contains(&MatchKind::Exact) {
+                answers.push(Ok(findings));
+                continue;
+            }
+            for prefix in &query.prefixes {
+                let (record, what) = if is_host(prefix) {
+                    (ip_records.get(&prefix.ip().to_string()), "ip")
+                } else {
+                    (cidr_records.get(&prefix.to_string()), "cidr")
+                };
+                let Some(Ok(Some(data))) = record else {
+                    continue;
+                };
+                let context = match serde_json::from_value::<NetRecord>(data.clone()) {
+                    Ok(NetRecord {
+                        global_threat_context: Some(context),
+                        ..
+                    }) => context,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        warn!("Skipping malformed Seclytics {what} record for {prefix}, {e}");
+                        continue;
+                    }
+                };
+                let Some(own) = context.verdict(&prefix.to_string()).filter(|v| v.malicious) else {
+                    continue;
+                };
+                let matched = PrefixMatch {
+                    kind: MatchKind::Exact,
+                    listed: *prefix,
+                };
+                match findings.prefixes.get_mut(prefix) {
+                    Some(verdict) => {
+                        verdict.confidence = verdict.confidence.max(own.confidence);
+                        verdict.reasons.extend(own.reasons);
+                        verdict.matches.push(matched);
+                    }
+                    None => {
+                        findings.prefixes.insert(
+                            *prefix,
+                            Verdict {
+                                matches: vec![matched],
+                                ..own
+                            },
+                        );
+                    }
+                }
+            }
+            answers.push(Ok(findings));
+        }
+        answers
+    }
+}
+
+fn is_host(prefix: &IpNetwork) -> bool {
+    match prefix {
+        IpNetwork::V4(net) => net.prefix() == 32,
+        IpNetwork::V6(net) => net.prefix() == 128,
+    }
+}
+
+/// Bulk answers are a list of records, bare or under `data`, each naming its `id`
+fn split_bulk(body: serde_json::Value) -> Result<HashMap<String, serde_json::Value>> {
+    let records = match body {
+        serde_json::Value::Array(records) => records,
+        serde_json::Value::Object(mut body) => match body.remove("data") {
+            Some(serde_json::Value::Array(records)) => records,
+            _ => return Err(anyhow!("Seclytics answered without a list of records")),
+        },
+        _ => return Err(anyhow!("Seclytics answered without a list of records")),
     };
-    if data["global_threat_context"]["cidrs"] == json!(null) {
-        warn!("Could not find data for AS{asn}");
-        return Ok((0, mal_asn));
+    Ok(records
+        .into_iter()
+        .filter_map(|record| {
+            let id = match &record["id"] {
+                serde_json::Value::String(id) => id.clone(),
+                serde_json::Value::Number(id) => id.to_string(),
+                _ => {
+                    warn!("Skipping a Seclytics record without an id");
+                    return None;
+                }
+            };
+            Some((id, record))
+        })
+        .collect())
+}
+
+/// What the Seclytics endpoints answer, fields we do not use are ignored
+pub(crate) mod models {
+    use super::{CONFIDENCE, SOURCE};
+    use crate::intel::Verdict;
+    use ipnetwork::IpNetwork;
+    use serde::{Deserialize, Deserializer};
+    use std::collections::BTreeMap;
+    use time::format_description::well_known::Rfc3339;
+    use time::OffsetDateTime;
+
+    /// A record of the `asns/` endpoint
+    #[derive(Deserialize, Debug, Clone, Default)]
+    pub(crate) struct AsnRecord {
+        /// `None` when Seclytics knows nothing about the ASN
+        #[serde(default)]
+        pub(crate) global_threat_context: Option<ThreatContext>,
     }
-    let known_bad: HashSet<IpNetwork> = data["global_threat_context"]["cidrs"]
-        .as_array()
-        .unwrap()
-        .iter()
-        .filter_map(|x| match x {
-            serde_json::Value::String(x) => Some(
-                IpNetwork::from_str(x)
-                    .ok()
-                    .expect(&*format!("couldn't parse, {x}")),
-            ),
-            _ => None,
+
+    /// A record of the `ips/` or the `cidrs/` endpoint, they answer alike
+    #[derive(Deserialize, Debug, Clone, Default)]
+    pub(crate) struct NetRecord {
+        #[serde(default)]
+        pub(crate) global_threat_context: Option<ThreatContext>,
+    }
+
+    /// What Seclytics has seen of an ASN, address or cidr
+    #[derive(Deserialize, Debug, Clone, Default)]
+    pub(crate) struct ThreatContext {
+        /// `None` when there are no categories, an empty list is a clean record
+        #[serde(default)]
+        pub(crate) categories: Option<Vec<String>>,
+        /// 0 to 100 per category, a single number is kept as `overall`
+        #[serde(default, deserialize_with = "scores")]
+        pub(crate) scores: BTreeMap<String, f64>,
+        #[serde(default, deserialize_with = "seen")]
+        pub(crate) first_seen: Option<OffsetDateTime>,
+        #[serde(default, deserialize_with = "seen")]
+        pub(crate) last_seen: Option<OffsetDateTime>,
+        /// Known bad cidrs related to it, a malformed one is kept as its raw entry
+        #[serde(default, deserialize_with = "cidrs")]
+        pub(crate) cidrs: Vec<Result<IpNetwork, String>>,
+    }
+
+    impl ThreatContext {
+        /// Bad when categorised `malicious`, as sure as the highest score. `None` without categories.
+        pub(crate) fn verdict(&self, what: &str) -> Option<Verdict> {
+            let categories = self.categories.as_ref()?;
+            let malicious = categories.iter().any(|c| c == "malicious");
+            let mut reasons = vec![];
+            if !categories.is_empty() {
+                reasons.push(format!("{what} categorised {}", categories.join(", ")));
+            }
+            if !self.scores.is_empty() {
+                let scores = self.scores.iter().map(|(c, s)| format!("{c} {s}"));
+                reasons.push(format!("scored {}", scores.collect::<Vec<_>>().join(", ")));
+            }
+            if let (Some(first), Some(last)) = (self.first_seen, self.last_seen) {
+                reasons.push(format!("seen {} to {}", first.date(), last.date()));
+            }
+            Some(Verdict {
+                malicious,
+                confidence: self.confidence(),
+                source: SOURCE.to_string(),
+                reasons: if malicious { reasons } else { vec![] },
+                matches: vec![],
+            })
+        }
+
+        pub(crate) fn confidence(&self) -> f64 {
+            self.scores
+                .values()
+                .copied()
+                .reduce(f64::max)
+                .map_or(CONFIDENCE, |score| (score / 100.0).clamp(0.0, 1.0))
+        }
+    }
+
+    fn scores<'de, D: Deserializer<'de>>(d: D) -> Result<BTreeMap<String, f64>, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Scores {
+            Overall(f64),
+            PerCategory(BTreeMap<String, f64>),
+        }
+        Ok(match Option::<Scores>::deserialize(d)? {
+            None => BTreeMap::new(),
+            Some(Scores::Overall(score)) => BTreeMap::from([("overall".to_string(), score)]),
+            Some(Scores::PerCategory(scores)) => scores,
         })
-        .collect();
-    debug!("{} known bad cidrs for AS{asn}", known_bad.len());
-
-    Ok((
-        cidr.iter()
-            .filter(|prefix| known_bad.contains(prefix))
-            .count(),
-        mal_asn,
-    ))
+    }
+
+    /// Every entry, one malformed entry does not cost the record
+    fn cidrs<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Result<IpNetwork, String>>, D::Error> {
+        let entries = Option::<Vec<serde_json::Value>>::deserialize(d)?.unwrap_or_default();
+        Ok(entries
+            .into_iter()
+            .map(|entry| match entry.as_str().map(str::parse::<IpNetwork>) {
+                Some(Ok(cidr)) => Ok(cidr),
+                _ => Err(entry.to_string()),
+            })
+            .collect())
+    }
+
+    /// RFC 3339 or unix seconds
+    fn seen<'de, D: Deserializer<'de>>(d: D) -> Result<Option<OffsetDateTime>, D::Error> {
+        use serde::de::Error;
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Seen {
+            Unix(i64),
+            Text(String),
+        }
+        match Option::<Seen>::deserialize(d)? {
+            None => Ok(None),
+            Some(Seen::Unix(secs)) => OffsetDateTime::from_unix_timestamp(secs)
+                .map(Some)
+                .map_err(D::Error::custom),
+            Some(Seen::Text(text)) => OffsetDateTime::parse(&text, &Rfc3339)
+                .map(Some)
+                .map_err(|e| D::Error::custom(format!("{text} is no RFC 3339 time, {e}"))),
+        }
+    }
 }
 
-fn url<const N: usize>(path: &str, mut options: [(String, &str); N]) -> Result<String>
-where
-    [(); N + 1]:,
-{
-    // try and remove later with generic_const_expr
-
-    // let sol: [(String, &str); N+1] = [options, [("".to_string(),"")]].iter().flat_map(|s| s.iter()).collect();
-    if options[N - 1].0 != "access_token".to_string() {
-        error!("Did not leave room for apikey, abort");
-        panic!()
-    } else {
-        options[N - 1].1 = &**SECLYTICS_API_TOKEN
-    }
-
-    let api = format!(
-        "{}{path}?{}",
-        *SECLYTICS_API_ENDPOINT,
-        options.map(|(s1, s2)| { s1 + "=" + s2 }).join("&")
-    );
+/// `path` under `endpoint`, queried with `options` and the api token
+fn url(endpoint: &str, token: &str, path: &str, options: &[(&str, &str)]) -> String {
+    let query = options
+        .iter()
+        .chain(&[("access_token", token)])
+        .map(|(key, value)| format!("{key}={value}"))
+        .join("&");
+    let api = format!("{endpoint}{path}?{query}");
     trace!("URL constructed, {}", api);
-    Ok(api)
+    api
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex as StdMutex};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    struct Answer {
+        status: u16,
+        retry_after: Option<u64>,
+        body: serde_json::Value,
+    }
+
+    /// Records of every asked for id, as the bulk endpoints answer them
+    fn records(ids: &[String]) -> Answer {
+        let records = ids
+            .iter()
+            .map(|id| {
+                serde_json::json!({
+                    "id": id,
+                    "global_threat_context": { "categories": ["malicious"], "scores": 90 }
+                })
+            })
+            .collect_vec();
+        Answer {
+            status: 200,
+            retry_after: None,
+            body: serde_json::json!({ "data": records }),
+        }
+    }
+
+    fn status(status: u16, retry_after: Option<u64>) -> Answer {
+        Answer {
+            status,
+            retry_after,
+            body: serde_json::json!({ "error": status }),
+        }
+    }
+
+    /// The ids a request asked for
+    type Request = Vec<String>;
+
+    /// A local stand-in for the Seclytics API, answering the n-th request with `answer(n, ids)`.
+    /// Returns its endpoint and every request it got.
+    async fn mock_api(
+        answer: impl Fn(usize, &[String]) -> Answer + Send + Sync + 'static,
+    ) -> (String, Arc<StdMutex<Vec<Request>>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = format!("http://{}/", listener.local_addr().unwrap());
+        let requests = Arc::new(StdMutex::new(vec![]));
+        let (answer, seen) = (Arc::new(answer), requests.clone());
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = vec![];
+                while !head.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0u8];
+                    if stream.read(&mut byte).await.unwrap() == 0 {
+                        break;
+                    }
+                    head.push(byte[0]);
+                }
+                let head = String::from_utf8(head).unwrap();
+                let target = head.split_whitespace().nth(1).unwrap_or_default();
+                let query = target.split_once('?').map_or("", |(_, q)| q);
+                let ids: Request = url::form_urlencoded::parse(query.as_bytes())
+                    .find(|(k, _)| k == "ids")
+                    .map(|(_, v)| v.split(',').map(str::to_string).collect())
+                    .unwrap_or_default();
+                let n = {
+                    let mut seen = seen.lock().unwrap();
+                    seen.push(ids.clone());
+                    seen.len() - 1
+                };
+                let answer = answer(n, &ids);
+                let body = answer.body.to_string();
+                let retry_after = answer
+                    .retry_after
+                    .map_or(String::new(), |secs| format!("Retry-After: {secs}\r\n"));
+                let response = format!(
+                    "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+                     Connection: close\r\n{retry_after}\r\n{body}",
+                    answer.status,
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        (endpoint, requests)
+    }
+
+    /// A cache directory of its own, removed again at the end of the test
+    struct CacheDir(PathBuf);
+
+    impl Drop for CacheDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn cache_dir() -> CacheDir {
+        CacheDir(std::env::temp_dir().join(format!("bgp_track-test-{}", uuid::Uuid::new_v4())))
+    }
+
+    fn seclytics(
+        endpoint: &str,
+        dir: &CacheDir,
+        mode: CacheMode,
+        retries: u32,
+        batch_size: usize,
+    ) -> Seclytics {
+        Seclytics::new(
+            Client::new(),
+            ResponseCache::new(dir.0.clone(), Duration::from_secs(3600), mode),
+            ApiOptions {
+                endpoint: Some(endpoint.to_string()),
+                token: Some("token".to_string()),
+                requests_per_second: 0.0,
+                retries,
+                batch_size,
+            },
+            &[MatchKind::Exact],
+        )
+    }
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn bulk_requests_are_split_into_batches() {
+        let (endpoint, requests) = mock_api(|_, ids| records(ids)).await;
+        let dir = cache_dir();
+        let api = seclytics(&endpoint, &dir, CacheMode::Refresh, 0, 2);
+        let found = api
+            .get_many("asns/", &ids(&["1", "2", "3", "4", "5", "3"]))
+            .await;
+
+        assert_eq!(found.len(), 5);
+        assert!(found.values().all(|r| matches!(r, Ok(Some(_)))));
+        let mut requests = requests.lock().unwrap().clone();
+        requests.sort();
+        assert_eq!(
+            requests,
+            vec![ids(&["1", "2"]), ids(&["3", "4"]), ids(&["5"])]
+        );
+    }
+
+    #[tokio::test]
+    async fn ids_without_a_record_are_none() {
+        let (endpoint, _) = mock_api(|_, ids| records(&ids[..1])).await;
+        let dir = cache_dir();
+        let api = seclytics(&endpoint, &dir, CacheMode::Refresh, 0, 10);
+        let found = api.get_many("asns/", &ids(&["1", "2"])).await;
+
+        assert!(matches!(found["1"], Ok(Some(_))));
+        assert!(matches!(found["2"], Ok(None)));
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let (endpoint, requests) = mock_api(|n, ids| match n {
+            0 => status(503, None),
+            1 => status(500, None),
+            _ => records(ids),
+        })
+        .await;
+        let dir = cache_dir();
+        let api = seclytics(&endpoint, &dir, CacheMode::Refresh, 2, 10);
+        let found = api.get_many("asns/", &ids(&["1"])).await;
+
+        assert!(matches!(found["1"], Ok(Some(_))));
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn too_many_requests_waits_retry_after() {
+        let (endpoint, requests) = mock_api(|n, ids| match n {
+            0 => status(429, Some(1)),
+            _ => records(ids),
+        })
+        .await;
+        let dir = cache_dir();
+        let api = seclytics(&endpoint, &dir, CacheMode::Refresh, 1, 10);
+        let started = Instant::now();
+        let found = api.get_many("asns/", &ids(&["1"])).await;
+
+        assert!(matches!(found["1"], Ok(Some(_))));
+        assert!(started.elapsed() >= Duration::from_secs(1));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_the_retries() {
+        let (endpoint, requests) = mock_api(|_, _| status(500, None)).await;
+        let dir = cache_dir();
+        let api = seclytics(&endpoint, &dir, CacheMode::Refresh, 1, 10);
+        let found = api.get_many("asns/", &ids(&["1", "2"])).await;
+
+        assert!(found.values().all(Result::is_err));
+        assert_eq!(requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (endpoint, requests) = mock_api(|_, _| status(401, None)).await;
+        let dir = cache_dir();
+        let api = seclytics(&endpoint, &dir, CacheMode::Refresh, 3, 10);
+        let found = api.get_many("asns/", &ids(&["1"])).await;
+
+        assert!(found["1"].is_err());
+        assert_eq!(requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn cached_records_are_not_asked_for_again() {
+        // Seclytics has no record of AS0
+        let (endpoint, requests) =
+            mock_api(|_, ids| records(&ids.iter().filter(|id| *id != "0").cloned().collect_vec()))
+                .await;
+        let dir = cache_dir();
+        let api = seclytics(&endpoint, &dir, CacheMode::Normal, 0, 10);
+        api.get_many("asns/", &ids(&["1", "2", "0"])).await;
+        let found = api.get_many("asns/", &ids(&["1", "2", "3", "0"])).await;
+
+        assert!(matches!(found["3"], Ok(Some(_))));
+        assert!(matches!(found["0"], Ok(None)));
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![ids(&["1", "2", "0"]), ids(&["3"])]
+        );
+
+        // offline answers what is cached, no record included, and leaves the rest unknown.
+        // A refresh asks for everything again.
+        let offline = seclytics(&endpoint, &dir, CacheMode::Offline, 0, 10);
+        let found = offline.get_many("asns/", &ids(&["1", "0", "4"])).await;
+        assert!(matches!(found["1"], Ok(Some(_))));
+        assert!(matches!(found["0"], Ok(None)));
+        assert!(found["4"].is_err());
+        assert_eq!(requests.lock().unwrap().len(), 2);
+
+        let refresh = seclytics(&endpoint, &dir, CacheMode::Refresh, 0, 10);
+        refresh.get_many("asns/", &ids(&["1"])).await;
+        assert_eq!(requests.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn lookups_use_the_asn_record() {
+        let (endpoint, _) = mock_api(|_, ids| records(ids)).await;
+        let dir = cache_dir();
+        let api = seclytics(&endpoint, &dir, CacheMode::Refresh, 0, 10);
+        let findings = api.lookup(13335, &[]).await.unwrap();
+        let verdict = findings.asn.unwrap();
+
+        assert!(verdict.malicious);
+        assert_eq!(verdict.confidence, 0.9);
+    }
+
+    #[test]
+    fn malformed_cidrs_are_skipped() {
+        let record: AsnRecord = serde_json::from_value(serde_json::json!({
+            "global_threat_context": {
+                "categories": ["malicious"],
+                "cidrs": ["192.0.2.0/24", "not a cidr", 42, "2001:db8::/32", "10.0.0.0/33"]
+            }
+        }))
+        .unwrap();
+        let context = record.global_threat_context.as_ref().unwrap();
+        assert_eq!(
+            context.cidrs,
+            vec![
+                Ok("192.0.2.0/24".parse::<IpNetwork>().unwrap()),
+                Err("\"not a cidr\"".to_string()),
+                Err("42".to_string()),
+                Ok("2001:db8::/32".parse().unwrap()),
+                Err("\"10.0.0.0/33\"".to_string()),
+            ]
+        );
+
+        let dir = cache_dir();
+        let api = seclytics("http://127.0.0.1:1/", &dir, CacheMode::Offline, 0, 10);
+        let prefix: IpNetwork = "192.0.2.0/24".parse().unwrap();
+        let findings = api.asn_findings(64496, &record, &[prefix]);
+        assert!(findings.prefixes.contains_key(&prefix));
+        assert_eq!(
+            findings.errors.iter().map(|e| e.to_string()).collect_vec(),
+            vec![
+                "Malformed Seclytics cidr \"not a cidr\" for AS64496",
+                "Malformed Seclytics cidr 42 for AS64496",
+                "Malformed Seclytics cidr \"10.0.0.0/33\" for AS64496",
+            ]
+        );
+    }
 }
@@ -0,0 +1,487 @@
+// Logs and Errors
+use anyhow::{anyhow, Context, Result};
+use log::info;
+
+// types
+use crate::bgp::FileReport;
+use crate::db_writer::types::{from_micros, to_micros, ASPathSeg, Announcement, UnixTimeStamp};
+use crate::db_writer::{PotentialHijack, ShortLivedFilter};
+use crate::pipeline::{FileBatch, FileStream};
+use crate::rollup::Rollup;
+use crate::storage::Storage;
+use ipnetwork::IpNetwork;
+use std::net::{IpAddr, Ipv6Addr};
+
+// sqlx stuff
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqliteRow};
+use sqlx::{Executor, Row};
+
+// bag of tools
+use itertools::Itertools;
+use std::collections::HashSet;
+use std::path::Path;
+use std::str::FromStr;
+
+const MICROS: i64 = 1_000_000;
+
+const SCHEMA: &str = r#"
+CREATE TABLE IF NOT EXISTS announcement
+(
+    id               INTEGER NOT NULL, -- see `row_id`, unique per timestamp
+    asn              INTEGER NOT NULL,
+    withdrawal       INTEGER NOT NULL,
+    timestamp        INTEGER NOT NULL, -- microseconds since the epoch
+    prefix           TEXT    NOT NULL,
+    family           INTEGER NOT NULL, -- 4 or 6, an IPv4 mapped range is no IPv6 range
+    net_start        BLOB    NOT NULL, -- first address as 16 bytes, IPv4 mapped, so blobs compare in address order
+    net_end          BLOB    NOT NULL, -- last address, same encoding
+    net_len          INTEGER NOT NULL, -- prefix length in the same 128 bit space
+    as_path_segments TEXT    NOT NULL  -- JSON
+);
+CREATE UNIQUE INDEX IF NOT EXISTS announcement_row ON announcement (timestamp, id);
+CREATE INDEX IF NOT EXISTS announcement_origin ON announcement (asn, prefix, withdrawal, timestamp);
+CREATE INDEX IF NOT EXISTS announcement_range ON announcement (family, net_start, net_end);
+CREATE TABLE IF NOT EXISTS prefix_hourly
+(
+    hour          INTEGER NOT NULL,
+    prefix        TEXT    NOT NULL,
+    origin        INTEGER NOT NULL,
+    announcements INTEGER NOT NULL,
+    withdrawals   INTEGER NOT NULL,
+    PRIMARY KEY (hour, prefix, origin)
+);
+CREATE TABLE IF NOT EXISTS prefix_daily
+(
+    day           INTEGER NOT NULL,
+    prefix        TEXT    NOT NULL,
+    origin        INTEGER NOT NULL,
+    announcements INTEGER NOT NULL,
+    withdrawals   INTEGER NOT NULL,
+    PRIMARY KEY (day, prefix, origin)
+);
+CREATE INDEX IF NOT EXISTS prefix_daily_origin ON prefix_daily (origin, day);
+CREATE TABLE IF NOT EXISTS peer_hourly
+(
+    hour          INTEGER NOT NULL,
+    collector     TEXT    NOT NULL,
+    peer_asn      INTEGER NOT NULL,
+    announcements INTEGER NOT NULL,
+    withdrawals   INTEGER NOT NULL,
+    PRIMARY KEY (hour, collector, peer_asn)
+);
+CREATE VIEW IF NOT EXISTS origin_prefixes_daily AS
+SELECT day, origin, count(*) AS prefixes
+FROM prefix_daily
+WHERE announcements > 0
+GROUP BY day, origin;
+CREATE TABLE IF NOT EXISTS ingested_file
+(
+    url             TEXT PRIMARY KEY,
+    status          TEXT    NOT NULL,
+    row_count       INTEGER NOT NULL,
+    checksum        INTEGER NOT NULL,
+    corrupt_records INTEGER NOT NULL DEFAULT 0,
+    error           TEXT,
+    ingested_at     TEXT    NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+"#;
+
+/// A single SQLite file holding the same tables as Postgres, for running without a server.
+/// Prefixes are also kept as a 128 bit address range so containment is a range lookup, matching `inet >>`.
+#[derive(Clone)]
+pub(crate) struct SqliteStore {
+    pool: SqlitePool,
+}
+
+impl SqliteStore {
+    pub(crate) async fn open(path: &Path) -> Result<Self> {
+        let pool = SqlitePool::connect_with(
+            SqliteConnectOptions::new()
+                .filename(path)
+                .create_if_missing(true)
+                .journal_mode(SqliteJournalMode::Wal),
+        )
+        .await
+        .with_context(|| format!("Failed while opening sqlite db {}", path.display()))?;
+        pool.execute(SCHEMA)
+            .await
+            .context("Failed while creating sqlite schema")?;
+        info!("Using sqlite store at {}", path.display());
+        Ok(SqliteStore { pool })
+    }
+}
+
+impl Storage for SqliteStore {
+    async fn ingested_files(&self) -> Result<HashSet<String>> {
+        Ok(sqlx::query_scalar::<_, String>(
+            "SELECT url FROM ingested_file WHERE status IN ('loaded', 'partial')",
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .collect())
+    }
+
+    /// Inserts the file's batches, their rollups and the `ingested_file` checkpoint in one transaction,
+    /// the collector is only kept in the rollups. There is a single SQLite writer, so holding it open waits on no one.
+    async fn write_file(
+        &self,
+        collector: &str,
+        mut batches: FileStream,
+    ) -> Result<(FileReport, u64)> {
+        let mut checksum = crc32fast::Hasher::new();
+        let mut copied = 0u64;
+        let mut tx = self.pool.begin().await?;
+        loop {
+            match batches.next() {
+                Some(FileBatch::Rows(rows)) => {
+                    copied += insert_rows(&mut tx, collector, &rows, &mut checksum).await?
+                }
+                Some(FileBatch::Restart) => {
+                    tx.rollback().await?;
+                    tx = self.pool.begin().await?;
+                    checksum = crc32fast::Hasher::new();
+                    copied = 0;
+                }
+                // dropping the transaction rolls it back
+                Some(FileBatch::Done(report)) if report.error.is_some() => return Ok((report, 0)),
+                Some(FileBatch::Done(report)) => {
+                    sqlx::query(
+                        r#"
+INSERT INTO ingested_file (url, status, row_count, checksum, corrupt_records)
+VALUES (?1, ?2, ?3, ?4, ?5)
+ON CONFLICT (url) DO UPDATE SET status          = excluded.status,
+                                row_count       = excluded.row_count,
+                                checksum        = excluded.checksum,
+                                corrupt_records = excluded.corrupt_records,
+                                error           = NULL,
+                                ingested_at     = CURRENT_TIMESTAMP
+"#,
+                    )
+                    .bind(&report.url)
+                    .bind(report.status())
+                    .bind(copied as i64)
+                    .bind(i64::from(checksum.finalize()))
+                    .bind(report.corrupt_records as i64)
+                    .execute(&mut *tx)
+                    .await?;
+                    tx.commit().await?;
+                    return Ok((report, copied));
+                }
+                None => return Err(anyhow!("The parser went away before the end of the file")),
+            }
+        }
+    }
+
+    async fn write_batch(&self, collector: &str, rows: Vec<Announcement>) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let copied = insert_rows(&mut tx, collector, &rows, &mut crc32fast::Hasher::new()).await?;
+        tx.commit().await?;
+        Ok(copied)
+    }
+
+    async fn record_failure(&self, report: &FileReport) -> Result<()> {
+        sqlx::query(
+            r#"
+INSERT INTO ingested_file (url, status, row_count, checksum, corrupt_records, error)
+VALUES (?1, ?2, 0, 0, ?3, ?4)
+ON CONFLICT (url) DO UPDATE SET status          = excluded.status,
+                                corrupt_records = excluded.corrupt_records,
+                                error           = excluded.error,
+                                ingested_at     = CURRENT_TIMESTAMP
+"#,
+        )
+        .bind(&report.url)
+        .bind(report.status())
+        .bind(report.corrupt_records as i64)
+        .bind(&report.error)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Indexes are created with the schema, only refresh the planner statistics
+    async fn finish_ingest(&self) -> Result<()> {
+        sqlx::query("ANALYZE").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    /// SQLite has no partitions, the rows are deleted
+    async fn prune(&self, before: UnixTimeStamp) -> Result<()> {
+        let deleted = sqlx::query("DELETE FROM announcement WHERE timestamp < ?")
+            .bind(before * MICROS)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        info!("Pruned {deleted} rows");
+        Ok(())
+    }
+
+    /// Same query as [`PotentialHijack::query_short_lived_window`], SQLite takes -1 as no limit.
+    /// The filter's prefixes are address ranges of the same family around or inside the announced one.
+    async fn short_lived_window(
+        &self,
+        window: UnixTimeStamp,
+        start: UnixTimeStamp,
+        stop: UnixTimeStamp,
+        limit: Option<i64>,
+        filter: &ShortLivedFilter,
+    ) -> Result<Vec<PotentialHijack>> {
+        let mut filters = String::new();
+        if !filter.asns.is_empty() {
+            let asns = filter.asns.iter().map(|asn| asn.to_string()).join(", ");
+            filters.push_str(&format!("  AND a1.asn IN ({asns})\n"));
+        }
+        let ranges = filter.prefixes.iter().map(|f| net_range(*f)).collect_vec();
+        if !ranges.is_empty() {
+            let around_or_inside = (0..ranges.len())
+                .map(|i| {
+                    let (family, lo, hi) = (6 + 3 * i, 7 + 3 * i, 8 + 3 * i);
+                    format!(
+                        "(a1.family = ?{family} AND ((a1.net_start >= ?{lo} AND a1.net_end <= ?{hi}) OR (a1.net_start <= ?{lo} AND a1.net_end >= ?{hi})))"
+                    )
+                })
+                .join("\n       OR ");
+            filters.push_str(&format!("  AND ({around_or_inside})\n"));
+        }
+        let sql = format!(
+            r#"
+SELECT a1.asn              AS asn,
+       a1.prefix           AS prefix,
+       MIN(a2.timestamp)   AS wd_time,
+       a1.timestamp        AS ann_time
+FROM announcement AS a1
+         JOIN announcement AS a2 ON a1.prefix = a2.prefix
+    AND a1.asn = a2.asn
+    AND a2.withdrawal = 1
+    AND a2.timestamp - a1.timestamp < ?1
+    AND a2.timestamp > a1.timestamp
+WHERE a1.withdrawal = 0
+  AND a2.timestamp < ?2
+  AND a1.timestamp < ?3
+  AND a2.timestamp >= ?4
+  AND a1.timestamp >= ?4
+{filters}GROUP BY a1.rowid
+LIMIT ?5
+"#
+        );
+        let mut query = sqlx::query(&sql)
+            .bind(window * MICROS)
+            .bind((stop + window) * MICROS) // beyond the window, no valid withdraws are present
+            .bind(stop * MICROS) // end of ann window
+            .bind(start * MICROS) // start of ann window, withdraws may be immediate
+            .bind(limit.unwrap_or(-1));
+        for (family, net_start, net_end, _) in ranges {
+            query = query
+                .bind(family)
+                .bind(net_start.to_vec())
+                .bind(net_end.to_vec());
+        }
+        query
+            .fetch_all(&self.pool)
+            .await?
+            .iter()
+            .map(|row| -> Result<PotentialHijack> {
+                Ok(PotentialHijack {
+                    prefix: IpNetwork::from_str(row.try_get("prefix")?)?,
+                    ann_time: from_micros(row.try_get("ann_time")?)?,
+                    wd_time: from_micros(row.try_get("wd_time")?)?,
+                    asn: row.try_get("asn")?,
+                })
+            })
+            .collect()
+    }
+
+    async fn ip_search(&self, ip: IpAddr) -> Result<Vec<Announcement>> {
+        let (family, addr, _, _) = net_range(IpNetwork::from(ip));
+        sqlx::query(
+            r#"
+SELECT id, asn, withdrawal, timestamp, prefix, as_path_segments
+FROM announcement
+WHERE family = ?2
+  AND net_start <= ?1
+  AND net_end >= ?1
+  AND net_len < 128
+  AND withdrawal = 0
+"#,
+        )
+        .bind(addr.to_vec())
+        .bind(family)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(from_row)
+        .collect()
+    }
+}
+
+/// Inserts `rows` and adds them to the rollups, rows stored before are skipped and not counted again
+async fn insert_rows(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    collector: &str,
+    rows: &[Announcement],
+    checksum: &mut crc32fast::Hasher,
+) -> Result<u64> {
+    let mut inserted = vec![];
+    for row in rows.iter() {
+        let segments = serde_json::to_string(&row.as_path_segments)?;
+        checksum.update(
+            format!(
+                "{},{},{},{:?},{},{segments}\n",
+                row.id, row.asn, row.withdrawal, row.timestamp, row.prefix
+            )
+            .as_bytes(),
+        );
+        let (family, net_start, net_end, net_len) = net_range(row.prefix);
+        let done = sqlx::query(
+            r#"
+INSERT OR IGNORE INTO announcement (id, asn, withdrawal, timestamp, prefix, family, net_start, net_end, net_len, as_path_segments)
+VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+"#,
+        )
+        .bind(row.id)
+        .bind(row.asn)
+        .bind(row.withdrawal)
+        .bind(to_micros(row.timestamp))
+        .bind(row.prefix.to_string())
+        .bind(family)
+        .bind(net_start.to_vec())
+        .bind(net_end.to_vec())
+        .bind(net_len)
+        .bind(segments)
+        .execute(&mut **tx)
+        .await?;
+        if done.rows_affected() > 0 {
+            inserted.push(row);
+        }
+    }
+    add_rollup(tx, collector, &Rollup::of(inserted.iter().copied())).await?;
+    Ok(inserted.len() as u64)
+}
+
+/// Adds the counts of `rollup` to the rollup tables, see [`Rollup`]
+async fn add_rollup(
+    tx: &mut sqlx::Transaction<'_, sqlx::Sqlite>,
+    collector: &str,
+    rollup: &Rollup,
+) -> Result<()> {
+    for (table, bucket, counts) in [
+        ("prefix_hourly", "hour", &rollup.prefix_hourly),
+        ("prefix_daily", "day", &rollup.prefix_daily),
+    ] {
+        let sql = format!(
+            r#"
+INSERT INTO {table} ({bucket}, prefix, origin, announcements, withdrawals)
+VALUES (?1, ?2, ?3, ?4, ?5)
+ON CONFLICT ({bucket}, prefix, origin) DO UPDATE SET announcements = announcements + excluded.announcements,
+                                                     withdrawals   = withdrawals + excluded.withdrawals
+"#
+        );
+        for count in counts {
+            sqlx::query(&sql)
+                .bind(count.start)
+                .bind(count.prefix.to_string())
+                .bind(count.origin)
+                .bind(count.announcements)
+                .bind(count.withdrawals)
+                .execute(&mut **tx)
+                .await?;
+        }
+    }
+    for count in rollup.peer_hourly.iter() {
+        sqlx::query(
+            r#"
+INSERT INTO peer_hourly (hour, collector, peer_asn, announcements, withdrawals)
+VALUES (?1, ?2, ?3, ?4, ?5)
+ON CONFLICT (hour, collector, peer_asn) DO UPDATE SET announcements = announcements + excluded.announcements,
+                                                      withdrawals   = withdrawals + excluded.withdrawals
+"#,
+        )
+        .bind(count.start)
+        .bind(collector)
+        .bind(count.peer_asn)
+        .bind(count.announcements)
+        .bind(count.withdrawals)
+        .execute(&mut **tx)
+        .await?;
+    }
+    Ok(())
+}
+
+/// The family of `net`, its first and last address as big endian IPv6 (IPv4 mapped) and its length in that space
+fn net_range(net: IpNetwork) -> (i64, [u8; 16], [u8; 16], i64) {
+    let (family, start, end, len) = match net {
+        IpNetwork::V4(n) => (
+            4,
+            n.network().to_ipv6_mapped(),
+            n.broadcast().to_ipv6_mapped(),
+            n.prefix() + 96,
+        ),
+        IpNetwork::V6(n) => (6, n.network(), last_v6(n.network(), n.prefix()), n.prefix()),
+    };
+    (family, start.octets(), end.octets(), i64::from(len))
+}
+
+fn last_v6(network: Ipv6Addr, prefix: u8) -> Ipv6Addr {
+    let host_bits = u128::MAX.checked_shr(u32::from(prefix)).unwrap_or(0);
+    Ipv6Addr::from(u128::from(network) | host_bits)
+}
+
+fn from_row(row: &SqliteRow) -> Result<Announcement> {
+    Ok(Announcement {
+        id: row.try_get("id")?,
+        asn: row.try_get("asn")?,
+        withdrawal: row.try_get("withdrawal")?,
+        timestamp: from_micros(row.try_get("timestamp")?)?,
+        prefix: IpNetwork::from_str(row.try_get("prefix")?)?,
+        as_path_segments: serde_json::from_str::<Vec<ASPathSeg>>(row.try_get("as_path_segments")?)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::OffsetDateTime;
+
+    /// An IPv4 prefix announced at 1000 and withdrawn 10 seconds later
+    async fn short_lived_v4(dir: &Path) -> SqliteStore {
+        let store = SqliteStore::open(&dir.join("bgp.sqlite")).await.unwrap();
+        let row = |id, withdrawal, secs| Announcement {
+            id,
+            asn: 64496,
+            withdrawal,
+            timestamp: OffsetDateTime::from_unix_timestamp(secs).unwrap(),
+            prefix: "192.0.2.0/24".parse().unwrap(),
+            as_path_segments: vec![],
+        };
+        let rows = vec![row(1, false, 1000), row(2, true, 1010)];
+        store.write_batch("rrc00", rows).await.unwrap();
+        store
+    }
+
+    #[tokio::test]
+    async fn ipv6_ranges_do_not_match_ipv4() {
+        let dir = std::env::temp_dir().join(format!("bgp_track-test-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let store = short_lived_v4(&dir).await;
+        let filter = |prefix: &str| ShortLivedFilter {
+            asns: vec![],
+            prefixes: vec![prefix.parse().unwrap()],
+        };
+
+        let found = store
+            .short_lived_window(60, 0, 2000, None, &filter("::/0"))
+            .await;
+        assert!(found.unwrap().is_empty());
+        let found = store
+            .short_lived_window(60, 0, 2000, None, &filter("0.0.0.0/0"))
+            .await;
+        assert_eq!(found.unwrap().len(), 1);
+
+        let mapped = store.ip_search("::ffff:192.0.2.1".parse().unwrap()).await;
+        assert!(mapped.unwrap().is_empty());
+        let v4 = store.ip_search("192.0.2.1".parse().unwrap()).await;
+        assert_eq!(v4.unwrap().len(), 1);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
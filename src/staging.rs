@@ -0,0 +1,372 @@
+// Logs and Errors
+use anyhow::{bail, Result};
+use log::{info, warn};
+
+// sqlx stuff
+use sqlx::PgPool;
+
+// types
+use crate::db_writer::types::UnixTimeStamp;
+
+// bag of tools
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// Serialises promotions, rollbacks, discards and prunes, any key only this module uses will do
+const STAGING_LOCK: i64 = 0x5747_4147;
+/// Rollup tables, their counts are kept apart per promotion and 0 while staged
+const ROLLUPS: [&str; 3] = ["prefix_hourly", "prefix_daily", "peer_hourly"];
+/// The `promotion` of staged rollup counts
+const STAGED: i64 = 0;
+
+/// Moves every staged day of `Announcement_new` into `Announcement` in one transaction.
+///
+/// Each staged partition is validated against the row count written alongside it, indexed, detached from
+/// `Announcement_new` and attached to `Announcement`. When `Announcement` already has that day its rows are
+/// merged in first, skipping any staged again, and the old partition is kept as `<name>_prev<promotion>` until
+/// the next promotion, so [`rollback`] can put it back. The staged rollup counts become the promotion's.
+/// Any failure leaves `Announcement` as it was and the staging in place.
+pub(crate) async fn promote(pool: &PgPool) -> Result<()> {
+    use std::time::Instant;
+    let now = Instant::now();
+    let mut tx = pool.begin().await?;
+    sqlx::query!("SELECT pg_advisory_xact_lock($1)", STAGING_LOCK)
+        .execute(&mut *tx)
+        .await?;
+
+    let staged =
+        sqlx::query!("SELECT partition, day, row_count FROM staged_partition ORDER BY day")
+            .fetch_all(&mut *tx)
+            .await?;
+    if staged.is_empty() {
+        info!("Nothing staged to promote");
+        return Ok(());
+    }
+
+    for part in staged.iter() {
+        let rows = count(&mut tx, &part.partition).await?;
+        if rows != part.row_count {
+            bail!(
+                "Staged partition {} has {rows} rows, {} were written, nothing was promoted",
+                part.partition,
+                part.row_count
+            );
+        }
+    }
+
+    // replaced partitions are only kept for rolling back the latest promotion
+    let stale = sqlx::query_scalar!(
+        r#"SELECT previous AS "previous!" FROM promoted_partition WHERE previous IS NOT NULL"#
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    sqlx::query!("UPDATE promoted_partition SET previous = NULL WHERE previous IS NOT NULL")
+        .execute(&mut *tx)
+        .await?;
+    for previous in stale {
+        sqlx::query(&format!(r#"DROP TABLE IF EXISTS "{previous}""#))
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    let promotion = sqlx::query_scalar!("INSERT INTO promotion DEFAULT VALUES RETURNING id")
+        .fetch_one(&mut *tx)
+        .await?;
+
+    for part in staged {
+        let live = part
+            .partition
+            .replacen("announcement_new_", "announcement_", 1);
+        sqlx::query!("SELECT index_announcement_partition($1)", part.partition)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(&format!(
+            r#"ALTER TABLE announcement_new DETACH PARTITION "{}""#,
+            part.partition
+        ))
+        .execute(&mut *tx)
+        .await?;
+
+        let exists =
+            sqlx::query_scalar!(r#"SELECT to_regclass($1) IS NOT NULL AS "exists!""#, live)
+                .fetch_one(&mut *tx)
+                .await?;
+        let previous = if exists {
+            let previous = format!("{live}_prev{promotion}");
+            // rows loaded again are already in the staged partition
+            let old_rows = sqlx::query(&format!(
+                r#"INSERT INTO "{}" SELECT * FROM "{live}" ON CONFLICT DO NOTHING"#,
+                part.partition
+            ))
+            .execute(&mut *tx)
+            .await?
+            .rows_affected() as i64;
+            sqlx::query(&format!(
+                r#"ALTER TABLE announcement DETACH PARTITION "{live}""#
+            ))
+            .execute(&mut *tx)
+            .await?;
+            sqlx::query!(
+                "SELECT rename_announcement_partition($1, $2)",
+                live,
+                previous
+            )
+            .execute(&mut *tx)
+            .await?;
+
+            let merged = count(&mut tx, &part.partition).await?;
+            if merged != part.row_count + old_rows {
+                bail!(
+                    "Merging {live} into {} gave {merged} rows, expected {}, nothing was promoted",
+                    part.partition,
+                    part.row_count + old_rows
+                );
+            }
+            Some(previous)
+        } else {
+            None
+        };
+
+        sqlx::query!(
+            "SELECT rename_announcement_partition($1, $2)",
+            part.partition,
+            live
+        )
+        .execute(&mut *tx)
+        .await?;
+        attach(&mut tx, &live, part.day).await?;
+        sqlx::query!(
+            "INSERT INTO promoted_partition (promotion, partition, day, previous) VALUES ($1, $2, $3, $4)",
+            promotion,
+            live,
+            part.day,
+            previous
+        )
+        .execute(&mut *tx)
+        .await?;
+        info!("Promoted {live}");
+    }
+
+    sqlx::query!("DELETE FROM staged_partition")
+        .execute(&mut *tx)
+        .await?;
+    for rollup in ROLLUPS {
+        sqlx::query(&format!("UPDATE {rollup} SET promotion = $1 WHERE promotion = $2"))
+            .bind(promotion)
+            .bind(STAGED)
+            .execute(&mut *tx)
+            .await?;
+    }
+    sqlx::query!(
+        "UPDATE ingested_file SET promotion = $1 WHERE promotion IS NULL",
+        promotion
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    info!(
+        ">>> Promoted staging as promotion {promotion} in: {:.2?}",
+        now.elapsed()
+    );
+    Ok(())
+}
+
+/// Undoes the latest promotion: its partitions are dropped and the ones they replaced put back.
+/// The files it promoted are forgotten by `ingested_file` and their rollup counts taken out, so the next
+/// `GetData` loads and counts them again. Live rows it promoted are lost.
+pub(crate) async fn rollback(pool: &PgPool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!("SELECT pg_advisory_xact_lock($1)", STAGING_LOCK)
+        .execute(&mut *tx)
+        .await?;
+
+    let Some(promotion) = sqlx::query_scalar!(
+        "SELECT id FROM promotion WHERE id = (SELECT max(id) FROM promotion) AND rolled_back_at IS NULL"
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    else {
+        bail!("No promotion to roll back, only the latest one can be and only once");
+    };
+
+    let promoted = sqlx::query!(
+        "SELECT partition, day, previous FROM promoted_partition WHERE promotion = $1",
+        promotion
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for part in promoted {
+        sqlx::query(&format!(
+            r#"ALTER TABLE announcement DETACH PARTITION "{}""#,
+            part.partition
+        ))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(&format!(r#"DROP TABLE "{}""#, part.partition))
+            .execute(&mut *tx)
+            .await?;
+        match part.previous {
+            Some(previous) => {
+                sqlx::query!(
+                    "SELECT rename_announcement_partition($1, $2)",
+                    previous,
+                    part.partition
+                )
+                .execute(&mut *tx)
+                .await?;
+                attach(&mut tx, &part.partition, part.day).await?;
+                info!("Restored {} from {previous}", part.partition);
+            }
+            None => info!("Dropped {}", part.partition),
+        }
+    }
+
+    forget_rollups(&mut tx, promotion).await?;
+    let forgotten = sqlx::query!("DELETE FROM ingested_file WHERE promotion = $1", promotion)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    sqlx::query!(
+        "UPDATE promotion SET rolled_back_at = now() WHERE id = $1",
+        promotion
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    info!("Rolled back promotion {promotion}, {forgotten} files will be loaded again");
+    Ok(())
+}
+
+/// Drops everything staged in `Announcement_new` and forgets the files that were loaded into it, with their rollup counts
+pub(crate) async fn discard(pool: &PgPool) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!("SELECT pg_advisory_xact_lock($1)", STAGING_LOCK)
+        .execute(&mut *tx)
+        .await?;
+
+    let partitions = sqlx::query_scalar!(
+        r#"SELECT inhrelid::regclass::text AS "partition!" FROM pg_inherits WHERE inhparent = 'announcement_new'::regclass"#
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for partition in partitions {
+        sqlx::query(&format!(r#"DROP TABLE "{partition}""#))
+            .execute(&mut *tx)
+            .await?;
+        warn!("Discarded {partition}");
+    }
+    sqlx::query!("DELETE FROM staged_partition")
+        .execute(&mut *tx)
+        .await?;
+    forget_rollups(&mut tx, STAGED).await?;
+    let forgotten = sqlx::query!("DELETE FROM ingested_file WHERE promotion IS NULL")
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    tx.commit().await?;
+    info!("Discarded staging, {forgotten} files will be loaded again");
+    Ok(())
+}
+
+/// Drops the day partitions of `Announcement` and `Announcement_new` that ended before `before`, with what
+/// staging knows of them. A pruned day is neither promoted nor rolled back any more, the partitions a promotion
+/// replaced for it go as well. The rollups keep their counts, the files stay ingested.
+pub(crate) async fn prune(pool: &PgPool, before: UnixTimeStamp) -> Result<()> {
+    let mut tx = pool.begin().await?;
+    sqlx::query!("SELECT pg_advisory_xact_lock($1)", STAGING_LOCK)
+        .execute(&mut *tx)
+        .await?;
+
+    let partitions = sqlx::query_scalar!(
+        r#"
+SELECT c.relname AS "partition!"
+FROM pg_inherits AS i
+         JOIN pg_class AS c ON c.oid = i.inhrelid
+WHERE i.inhparent IN ('announcement'::regclass, 'announcement_new'::regclass)
+  AND c.relname ~ '_p\d{8}$'
+  AND extract(EPOCH FROM to_date(right(c.relname, 8), 'YYYYMMDD'))::bigint + 86400 <= $1
+"#,
+        before
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    if partitions.is_empty() {
+        info!("Nothing to prune");
+        return Ok(());
+    }
+
+    for partition in partitions.iter() {
+        sqlx::query(&format!(r#"DROP TABLE "{partition}""#))
+            .execute(&mut *tx)
+            .await?;
+        info!("Dropped partition {partition}");
+    }
+    sqlx::query!(
+        "DELETE FROM staged_partition WHERE partition = ANY($1)",
+        &partitions
+    )
+    .execute(&mut *tx)
+    .await?;
+    let replaced = sqlx::query_scalar!(
+        "DELETE FROM promoted_partition WHERE partition = ANY($1) RETURNING previous",
+        &partitions
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    for previous in replaced.into_iter().flatten() {
+        sqlx::query(&format!(r#"DROP TABLE IF EXISTS "{previous}""#))
+            .execute(&mut *tx)
+            .await?;
+        info!("Dropped partition {previous}");
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Takes the rollup counts of `promotion` out, [`STAGED`] for those not promoted yet
+async fn forget_rollups(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    promotion: i64,
+) -> Result<()> {
+    for rollup in ROLLUPS {
+        let buckets = sqlx::query(&format!("DELETE FROM {rollup} WHERE promotion = $1"))
+            .bind(promotion)
+            .execute(&mut **tx)
+            .await?
+            .rows_affected();
+        info!("Took {buckets} buckets out of {rollup}");
+    }
+    Ok(())
+}
+
+/// Holds promotions, rollbacks, discards and prunes off until `tx` ends, for writers staging rows in it.
+/// Writers only wait on each other while one of those runs.
+pub(crate) async fn hold_off(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>) -> Result<()> {
+    sqlx::query!("SELECT pg_advisory_xact_lock_shared($1)", STAGING_LOCK)
+        .execute(&mut **tx)
+        .await?;
+    Ok(())
+}
+
+async fn count(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, table: &str) -> Result<i64> {
+    Ok(
+        sqlx::query_scalar(&format!(r#"SELECT count(*) FROM "{table}""#))
+            .fetch_one(&mut **tx)
+            .await?,
+    )
+}
+
+async fn attach(
+    tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+    table: &str,
+    day: OffsetDateTime,
+) -> Result<()> {
+    sqlx::query(&format!(
+        r#"ALTER TABLE announcement ATTACH PARTITION "{table}" FOR VALUES FROM ('{}') TO ('{}')"#,
+        day.format(&Rfc3339)?,
+        (day + time::Duration::DAY).format(&Rfc3339)?
+    ))
+    .execute(&mut **tx)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,179 @@
+// Logs and Errors
+use anyhow::{anyhow, Result};
+
+// types
+use crate::bgp::FileReport;
+use crate::config::Database;
+use crate::db_writer::types::{Announcement, UnixTimeStamp};
+use crate::db_writer::{open_db, PotentialHijack, ShortLivedFilter};
+use crate::file_store::FileStore;
+use crate::pipeline::FileStream;
+use crate::sqlite_store::SqliteStore;
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::IpAddr;
+
+/// Where announcements are written to by `GetData`/`Live` and read back from by the detectors.
+/// Futures are `Send` so a store can be handed to the writer task.
+pub(crate) trait Storage: Clone + Send + Sync + 'static {
+    /// URLs of the MRT files that do not need loading again
+    fn ingested_files(&self) -> impl Future<Output = Result<HashSet<String>>> + Send;
+
+    /// Stores the batches of one MRT file seen by `collector` together with its checkpoint, so a crash never
+    /// leaves a file half loaded or loaded but unrecorded. A [`crate::pipeline::FileBatch::Restart`] drops the rows stored so far.
+    /// Returns the file's report and the rows stored, nothing is kept of a file whose report has an error.
+    fn write_file(
+        &self,
+        collector: &str,
+        batches: FileStream,
+    ) -> impl Future<Output = Result<(FileReport, u64)>> + Send;
+
+    /// Stores one batch of live `rows` seen by `collector`, returning how many were stored
+    fn write_batch(
+        &self,
+        collector: &str,
+        rows: Vec<Announcement>,
+    ) -> impl Future<Output = Result<u64>> + Send;
+
+    /// Records a file that could not be parsed or stored, see [`FileReport::status`]
+    fn record_failure(&self, report: &FileReport) -> impl Future<Output = Result<()>> + Send;
+
+    /// Called once an ingest run has written everything, e.g. to build indexes
+    fn finish_ingest(&self) -> impl Future<Output = Result<()>> + Send;
+
+    /// Drops all announcements older than `before`, which falls on the start of a UTC day
+    fn prune(&self, before: UnixTimeStamp) -> impl Future<Output = Result<()>> + Send;
+
+    /// Announcements made in `[start, stop)` that were withdrawn again within `window` seconds,
+    /// `limit` caps the number of results that pass the `filter`
+    fn short_lived_window(
+        &self,
+        window: UnixTimeStamp,
+        start: UnixTimeStamp,
+        stop: UnixTimeStamp,
+        limit: Option<i64>,
+        filter: &ShortLivedFilter,
+    ) -> impl Future<Output = Result<Vec<PotentialHijack>>> + Send;
+
+    /// Announcements (not withdrawals) of any prefix containing `ip`
+    fn ip_search(&self, ip: IpAddr) -> impl Future<Output = Result<Vec<Announcement>>> + Send;
+}
+
+#[derive(clap::ValueEnum, serde::Serialize, serde::Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Store {
+    /// Postgres at `DATABASE_URL`
+    Postgres,
+    /// Arrow IPC files under `database.data_dir`, no server needed
+    Files,
+    /// A single SQLite file at `database.sqlite_path`, no server needed
+    Sqlite,
+}
+
+/// The [`Storage`] picked on the command line
+#[derive(Clone)]
+pub(crate) enum Backend {
+    Postgres(sqlx::PgPool),
+    Files(FileStore),
+    Sqlite(SqliteStore),
+}
+
+impl Backend {
+    /// The url, migrations and `writers` only concern Postgres, SQLite creates its schema when opened
+    pub(crate) async fn open(database: &Database, writers: usize) -> Result<Self> {
+        Ok(match database.store {
+            Store::Postgres => {
+                let url = database.url.as_deref().ok_or_else(|| {
+                    anyhow!("No postgres to connect to, set DATABASE_URL, --database-url or database.url")
+                })?;
+                Backend::Postgres(open_db(url, database.migrate, writers).await?)
+            }
+            Store::Files => Backend::Files(FileStore::open(database.data_dir.clone())?),
+            Store::Sqlite => Backend::Sqlite(SqliteStore::open(&database.sqlite_path).await?),
+        })
+    }
+}
+
+impl Storage for Backend {
+    async fn ingested_files(&self) -> Result<HashSet<String>> {
+        match self {
+            Backend::Postgres(pool) => pool.ingested_files().await,
+            Backend::Files(files) => files.ingested_files().await,
+            Backend::Sqlite(sqlite) => sqlite.ingested_files().await,
+        }
+    }
+
+    async fn write_file(&self, collector: &str, batches: FileStream) -> Result<(FileReport, u64)> {
+        match self {
+            Backend::Postgres(pool) => pool.write_file(collector, batches).await,
+            Backend::Files(files) => files.write_file(collector, batches).await,
+            Backend::Sqlite(sqlite) => sqlite.write_file(collector, batches).await,
+        }
+    }
+
+    async fn write_batch(&self, collector: &str, rows: Vec<Announcement>) -> Result<u64> {
+        match self {
+            Backend::Postgres(pool) => pool.write_batch(collector, rows).await,
+            Backend::Files(files) => files.write_batch(collector, rows).await,
+            Backend::Sqlite(sqlite) => sqlite.write_batch(collector, rows).await,
+        }
+    }
+
+    async fn record_failure(&self, report: &FileReport) -> Result<()> {
+        match self {
+            Backend::Postgres(pool) => pool.record_failure(report).await,
+            Backend::Files(files) => files.record_failure(report).await,
+            Backend::Sqlite(sqlite) => sqlite.record_failure(report).await,
+        }
+    }
+
+    async fn finish_ingest(&self) -> Result<()> {
+        match self {
+            Backend::Postgres(pool) => pool.finish_ingest().await,
+            Backend::Files(files) => files.finish_ingest().await,
+            Backend::Sqlite(sqlite) => sqlite.finish_ingest().await,
+        }
+    }
+
+    async fn prune(&self, before: UnixTimeStamp) -> Result<()> {
+        match self {
+            Backend::Postgres(pool) => pool.prune(before).await,
+            Backend::Files(files) => files.prune(before).await,
+            Backend::Sqlite(sqlite) => sqlite.prune(before).await,
+        }
+    }
+
+    async fn short_lived_window(
+        &self,
+        window: UnixTimeStamp,
+        start: UnixTimeStamp,
+        stop: UnixTimeStamp,
+        limit: Option<i64>,
+        filter: &ShortLivedFilter,
+    ) -> Result<Vec<PotentialHijack>> {
+        match self {
+            Backend::Postgres(pool) => {
+                pool.short_lived_window(window, start, stop, limit, filter)
+                    .await
+            }
+            Backend::Files(files) => {
+                files
+                    .short_lived_window(window, start, stop, limit, filter)
+                    .await
+            }
+            Backend::Sqlite(sqlite) => {
+                sqlite
+                    .short_lived_window(window, start, stop, limit, filter)
+                    .await
+            }
+        }
+    }
+
+    async fn ip_search(&self, ip: IpAddr) -> Result<Vec<Announcement>> {
+        match self {
+            Backend::Postgres(pool) => pool.ip_search(ip).await,
+            Backend::Files(files) => files.ip_search(ip).await,
+            Backend::Sqlite(sqlite) => sqlite.ip_search(ip).await,
+        }
+    }
+}